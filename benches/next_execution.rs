@@ -0,0 +1,218 @@
+use chrono::prelude::*;
+use chrono::Duration;
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use crust::expr::*;
+use crust::schedule::Schedule;
+
+fn field(unit: CronUnit, values: &[u32]) -> CronExpr {
+    CronExpr(unit, CronInterval::Multiple(values.iter().cloned().collect()))
+}
+
+fn schedules() -> Vec<(&'static str, Schedule)> {
+    vec![
+        (
+            "*/15 * * * *",
+            Schedule {
+                minute: field(CronUnit::Minute, &[0, 15, 30, 45]),
+                ..Schedule::default()
+            },
+        ),
+        (
+            "30 4 1,15 * 5",
+            Schedule {
+                minute: field(CronUnit::Minute, &[30]),
+                hour: field(CronUnit::Hour, &[4]),
+                dom: field(CronUnit::DayOfMonth, &[1, 15]),
+                dow: field(CronUnit::DayOfWeek, &[5]),
+                ..Schedule::default()
+            },
+        ),
+        (
+            "0 12 29 2 *",
+            Schedule {
+                minute: field(CronUnit::Minute, &[0]),
+                hour: field(CronUnit::Hour, &[12]),
+                dom: field(CronUnit::DayOfMonth, &[29]),
+                month: field(CronUnit::Month, &[2]),
+                ..Schedule::default()
+            },
+        ),
+    ]
+}
+
+/// The schedules of `schedules` as sorted lists of values, `None` for `*`,
+/// searched the way crust did before fields were bitmasks. Kept as the
+/// baseline for `Schedule::next`.
+struct ListSchedule {
+    second: Option<Vec<u32>>,
+    minute: Option<Vec<u32>>,
+    hour: Option<Vec<u32>>,
+    dom: Option<Vec<u32>>,
+    month: Option<Vec<u32>>,
+    dow: Option<Vec<u32>>,
+}
+
+fn contains(field: &Option<Vec<u32>>, value: u32) -> bool {
+    field.as_ref().is_none_or(|values| values.contains(&value))
+}
+
+/// The next value after `value`, wrapping around to the first
+fn next_from(field: &Option<Vec<u32>>, value: u32, min: u32, max: u32) -> u32 {
+    match field {
+        None if value >= max => min,
+        None => value + 1,
+        Some(values) => match values.iter().find(|v| **v > value) {
+            Some(next) => *next,
+            None => values[0],
+        },
+    }
+}
+
+impl ListSchedule {
+    fn new(fields: &str) -> ListSchedule {
+        let list = |field: &str, min: u32, max: u32| -> Option<Vec<u32>> {
+            match field {
+                "*" => None,
+                _ => match field.strip_prefix("*/") {
+                    Some(step) => Some((min..=max).step_by(step.parse().unwrap()).collect()),
+                    None => Some(field.split(',').map(|v| v.parse().unwrap()).collect()),
+                },
+            }
+        };
+        let fields: Vec<&str> = fields.split_whitespace().collect();
+        ListSchedule {
+            second: Some(vec![0]),
+            minute: list(fields[0], 0, 59),
+            hour: list(fields[1], 0, 23),
+            dom: list(fields[2], 1, 31),
+            month: list(fields[3], 1, 12),
+            dow: list(fields[4], 0, 6),
+        }
+    }
+
+    fn day_matches(&self, date: NaiveDate) -> bool {
+        let dom = contains(&self.dom, date.day());
+        let dow = contains(&self.dow, date.weekday().num_days_from_sunday());
+        if self.dom.is_some() && self.dow.is_some() {
+            dom || dow
+        } else {
+            dom && dow
+        }
+    }
+
+    fn next(&self, now: DateTime<Local>) -> Option<DateTime<Local>> {
+        let mut t = now.naive_local().with_nanosecond(0).unwrap();
+        loop {
+            if !contains(&self.month, t.month()) {
+                let (year, month) = match t.month() {
+                    12 => (t.year() + 1, 1),
+                    month => (t.year(), month + 1),
+                };
+                t = NaiveDate::from_ymd(year, month, 1).and_hms(0, 0, 0);
+                continue;
+            }
+            if !self.day_matches(t.date()) {
+                t = t.date().succ().and_hms(0, 0, 0);
+                continue;
+            }
+            if !contains(&self.hour, t.hour()) {
+                let hour = next_from(&self.hour, t.hour(), 0, 23);
+                t = if hour > t.hour() {
+                    t.date().and_hms(hour, 0, 0)
+                } else {
+                    t.date().succ().and_hms(0, 0, 0)
+                };
+                continue;
+            }
+            if !contains(&self.minute, t.minute()) {
+                let minute = next_from(&self.minute, t.minute(), 0, 59);
+                t = if minute > t.minute() {
+                    t.date().and_hms(t.hour(), minute, 0)
+                } else {
+                    t.date().and_hms(t.hour(), 0, 0) + Duration::hours(1)
+                };
+                continue;
+            }
+            if !contains(&self.second, t.second()) {
+                let second = next_from(&self.second, t.second(), 0, 59);
+                t = if second > t.second() {
+                    t.with_second(second).unwrap()
+                } else {
+                    t.date().and_hms(t.hour(), t.minute(), 0) + Duration::minutes(1)
+                };
+                continue;
+            }
+            match Local.from_local_datetime(&t).earliest() {
+                Some(next) => return Some(next),
+                None => t += Duration::seconds(1),
+            }
+        }
+    }
+}
+
+/// How many run times each iteration computes, a hundred at a time from
+/// the same start so that rare schedules stay within a few centuries.
+/// Criterion takes at least a hundred samples, so every schedule is timed
+/// over millions of run times.
+const RUNS: u64 = 10_000;
+
+fn run_times<F>(next: F) -> DateTime<Local>
+where
+    F: Fn(DateTime<Local>) -> Option<DateTime<Local>>,
+{
+    let start = Local.ymd(2019, 1, 1).and_hms(0, 0, 0);
+    let mut t = start;
+    for run in 0..RUNS {
+        if run % 100 == 0 {
+            t = start;
+        }
+        t = next(t + Duration::seconds(1)).unwrap();
+    }
+    t
+}
+
+/// The search for the next run on bitmasks against the search on lists it
+/// replaced
+fn next_execution(c: &mut Criterion) {
+    let mut group = c.benchmark_group("next_execution");
+    group.throughput(Throughput::Elements(RUNS));
+    for (name, schedule) in schedules() {
+        let list = ListSchedule::new(name);
+        assert_eq!(
+            run_times(|t| schedule.next(t)),
+            run_times(|t| list.next(t)),
+            "{}",
+            name
+        );
+        group.bench_with_input(BenchmarkId::new("bitmask", name), &schedule, |b, schedule| {
+            b.iter(|| black_box(run_times(|t| schedule.next(t))))
+        });
+        group.bench_with_input(BenchmarkId::new("list", name), &list, |b, list| {
+            b.iter(|| black_box(run_times(|t| list.next(t))))
+        });
+    }
+    group.finish();
+}
+
+/// The lookup of the next value in a field, as a bitmask and as the sorted
+/// list it used to be
+fn next_value(c: &mut Criterion) {
+    let values: Vec<u32> = (0..60).filter(|n| n % 7 == 0).collect();
+    let bits: Bits = values.iter().cloned().collect();
+    let mut group = c.benchmark_group("next_value");
+    group.bench_function("bitmask", |b| {
+        b.iter(|| (0..60).map(|n| bits.next(black_box(n)).unwrap_or(0)).sum::<u32>())
+    });
+    group.bench_function("list", |b| {
+        b.iter(|| {
+            (0..60)
+                .map(|n| values.iter().find(|v| **v >= black_box(n)).cloned().unwrap_or(0))
+                .sum::<u32>()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, next_execution, next_value);
+criterion_main!(benches);
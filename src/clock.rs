@@ -0,0 +1,96 @@
+//! Sleeping until a wall clock time while watching for clock jumps.
+//!
+//! Sleeps are measured on the monotonic clock, which neither follows changes
+//! of the system time nor advances while the machine is suspended. Sleeping
+//! in short slices and comparing both clocks after each slice lets the
+//! scheduler notice when its idea of the wall time went stale.
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::Instant;
+
+use chrono::prelude::*;
+use chrono::Duration;
+
+use crate::scheduler::Message;
+
+/// The longest crust sleeps before checking the clocks again
+pub(crate) const SLICE_SECONDS: i64 = 10;
+
+/// How far the clocks may drift apart during one slice before it counts as a
+/// jump, this absorbs scheduling delays and NTP slewing
+const JUMP_THRESHOLD_SECONDS: i64 = 2;
+
+#[derive(Debug, PartialEq)]
+pub enum Wakeup {
+    Reached,
+    /// The wall clock moved by this much more than the monotonic clock
+    Jumped(Duration),
+    Quit,
+}
+
+pub fn sleep_until(target: DateTime<Local>, rx: &Receiver<Message>) -> Wakeup {
+    loop {
+        let wall_before = Local::now();
+        let remaining = target - wall_before;
+        if remaining <= Duration::zero() {
+            return Wakeup::Reached;
+        }
+        let slice = std::cmp::min(remaining, Duration::seconds(SLICE_SECONDS));
+
+        let mono_before = Instant::now();
+        match rx.recv_timeout(slice.to_std().unwrap()) {
+            Ok(Message::Quit) => return Wakeup::Quit,
+            // The handles are gone, nothing can cancel the job anymore
+            Err(RecvTimeoutError::Disconnected) => thread::sleep(slice.to_std().unwrap()),
+            Err(RecvTimeoutError::Timeout) => {}
+        }
+        let mono = Duration::from_std(mono_before.elapsed()).unwrap();
+        let wall = Local::now() - wall_before;
+
+        if let Some(jump) = detect_jump(mono, wall) {
+            return Wakeup::Jumped(jump);
+        }
+    }
+}
+
+fn detect_jump(mono: Duration, wall: Duration) -> Option<Duration> {
+    let drift = wall - mono;
+    if drift.num_seconds().abs() >= JUMP_THRESHOLD_SECONDS {
+        Some(drift)
+    } else {
+        None
+    }
+}
+
+/// A random duration between zero and `max`
+pub fn random_duration(max: Duration) -> Duration {
+    let millis = max.num_milliseconds();
+    if millis <= 0 {
+        return Duration::zero();
+    }
+    // RandomState is seeded randomly, which is all the randomness needed here
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_i64(Local::now().timestamp_nanos());
+    Duration::milliseconds((hasher.finish() % millis as u64) as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jumps() {
+        let slice = Duration::seconds(10);
+        assert_eq!(detect_jump(slice, slice + Duration::milliseconds(300)), None);
+        assert_eq!(
+            detect_jump(slice, slice + Duration::hours(8)),
+            Some(Duration::hours(8))
+        );
+        assert_eq!(
+            detect_jump(slice, slice - Duration::minutes(5)),
+            Some(-Duration::minutes(5))
+        );
+    }
+}
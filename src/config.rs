@@ -0,0 +1,393 @@
+//! Settings of the daemon, read from `$XDG_CONFIG_HOME/crust/config.toml`.
+//! Every setting is optional and flags on the command line take precedence.
+//! With `system = true` crust replaces the system cron and the defaults
+//! change to the paths of the system, see `system`.
+//!
+//! ```toml
+//! shell = "/bin/bash"
+//! crontabs = ["~/.config/crontab", "~/projects/crontab"]
+//! crontab_dirs = ["~/.config/crust/cron.d", "~/projects/cron.d"]
+//! log_dir = "~/.local/state/crust"
+//! history_retention = "30d"
+//! timeout = "2h"
+//! max_jobs = 4
+//!
+//! [env]
+//! PATH = "/usr/local/bin:/usr/bin:/bin"
+//!
+//! [group_limits]
+//! backup = 1
+//!
+//! [mail]
+//! to = "ops@example.com"
+//! command = "sendmail -t -i"
+//! ```
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use chrono::Duration;
+use serde::{Deserialize, Deserializer};
+
+use crate::crontab::is_env_name;
+use crate::mail::Mailer;
+use crate::parser::parse_duration;
+use crate::system;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Runs the commands of entries, unless their crontab sets `SHELL`
+    pub shell: Option<PathBuf>,
+    /// Variables for every job, the variables of a crontab take precedence
+    pub env: BTreeMap<String, String>,
+    /// Where the history and the output of runs are kept
+    pub log_dir: Option<PathBuf>,
+    /// How long runs stay in the history, forever if not set
+    #[serde(deserialize_with = "duration")]
+    pub history_retention: Option<Duration>,
+    /// Timeout of jobs without a timeout option
+    #[serde(deserialize_with = "duration")]
+    pub timeout: Option<Duration>,
+    /// Read crontabs as if they started with `!seconds(on)`
+    pub seconds: bool,
+    pub max_jobs: Option<usize>,
+    pub group_limits: HashMap<String, usize>,
+    /// Mail the output of runs, to `MAILTO` or the address given here
+    pub mail: Option<Mail>,
+    /// The crontabs to look for, those that exist are read and the first
+    /// of them is the one that is edited
+    pub crontabs: Vec<PathBuf>,
+    /// Directories of crontabs, `$XDG_CONFIG_HOME/crust/cron.d` if not set
+    pub crontab_dirs: Option<Vec<PathBuf>>,
+    /// Run as the system cron, with a user column in the crontabs
+    pub system: bool,
+    /// The crontabs of users in system mode, /var/spool/cron/crontabs if not
+    /// set
+    pub spool_dirs: Option<Vec<PathBuf>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Mail {
+    /// Used for crontabs that do not set `MAILTO`
+    pub to: Option<String>,
+    /// Used for crontabs that do not set `MAILFROM`
+    pub from: Option<String>,
+    /// Reads the message with its headers from stdin
+    #[serde(default = "default_mail_command")]
+    pub command: String,
+}
+
+fn default_mail_command() -> String {
+    String::from("sendmail -t -i")
+}
+
+fn duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+    let text = String::deserialize(deserializer)?;
+    match parse_duration(&text) {
+        Some(duration) if duration > Duration::zero() => Ok(Some(duration)),
+        _ => Err(serde::de::Error::custom(format!(
+            "invalid duration `{}`, expected e.g. \"30d\" or \"1h30m\"",
+            text
+        ))),
+    }
+}
+
+/// Why the config could not be used
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, io::Error),
+    Toml(PathBuf, toml::de::Error),
+    Invalid(PathBuf, String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "Error reading config {}: {}", path.display(), e),
+            ConfigError::Toml(path, e) => write!(f, "Error in config {}: {}", path.display(), e),
+            ConfigError::Invalid(path, message) => {
+                write!(f, "Error in config {}: {}", path.display(), message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// `$XDG_CONFIG_HOME/crust/config.toml`
+    pub fn default_path() -> PathBuf {
+        config_home().join("crust").join("config.toml")
+    }
+
+    /// Read and check the config at `path`
+    pub fn load(path: &Path) -> Result<Config, ConfigError> {
+        let text = fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_owned(), e))?;
+        Config::parse(path, &text)
+    }
+
+    fn parse(path: &Path, text: &str) -> Result<Config, ConfigError> {
+        let mut config: Config =
+            toml::from_str(text).map_err(|e| ConfigError::Toml(path.to_owned(), e))?;
+        for path in config
+            .shell
+            .iter_mut()
+            .chain(config.log_dir.iter_mut())
+            .chain(config.crontabs.iter_mut())
+            .chain(config.crontab_dirs.iter_mut().flatten())
+            .chain(config.spool_dirs.iter_mut().flatten())
+        {
+            *path = expand(&path.to_string_lossy());
+        }
+        config
+            .validate()
+            .map_err(|message| ConfigError::Invalid(path.to_owned(), message))?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if let Some(shell) = &self.shell {
+            if !shell.is_absolute() || !shell.is_file() {
+                return Err(format!(
+                    "`shell` is not an absolute path to a file: {}",
+                    shell.display()
+                ));
+            }
+        }
+        if let Some(name) = self.env.keys().find(|name| !is_env_name(name)) {
+            return Err(format!("`{}` in [env] is not a variable name", name));
+        }
+        if let Some(log_dir) = &self.log_dir {
+            if log_dir.exists() && !log_dir.is_dir() {
+                return Err(format!(
+                    "`log_dir` is not a directory: {}",
+                    log_dir.display()
+                ));
+            }
+        }
+        if self.max_jobs == Some(0) {
+            return Err(String::from("`max_jobs` must be at least 1"));
+        }
+        if let Some((group, _)) = self.group_limits.iter().find(|(_, max)| **max == 0) {
+            return Err(format!("The limit of group `{}` must be at least 1", group));
+        }
+        if let Some(mail) = &self.mail {
+            if mail.command.trim().is_empty() {
+                return Err(String::from("`command` in [mail] is empty"));
+            }
+        }
+        if self.crontabs.iter().any(|path| path.as_os_str().is_empty()) {
+            return Err(String::from("`crontabs` contains an empty path"));
+        }
+        if self.spool_dirs.is_some() && !self.system {
+            return Err(String::from("`spool_dirs` needs `system = true`"));
+        }
+        for dir in self.crontab_dirs.iter().flatten() {
+            if dir.as_os_str().is_empty() || (dir.exists() && !dir.is_dir()) {
+                return Err(format!(
+                    "`crontab_dirs` contains something other than a directory: {}",
+                    dir.display()
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// The first of the crontabs that exists, or the first one if none does
+    pub fn crontab(&self) -> Option<&Path> {
+        self.crontabs
+            .iter()
+            .find(|path| path.exists())
+            .or_else(|| self.crontabs.first())
+            .map(PathBuf::as_path)
+    }
+
+    /// The crontabs that exist, or the first one if none does
+    pub fn crontab_files(&self) -> Vec<PathBuf> {
+        let existing: Vec<PathBuf> = self
+            .crontabs
+            .iter()
+            .filter(|path| path.exists())
+            .cloned()
+            .collect();
+        if !existing.is_empty() {
+            existing
+        } else if self.crontabs.is_empty() && self.system {
+            vec![PathBuf::from(system::SYSTEM_CRONTAB)]
+        } else {
+            self.crontab().map(PathBuf::from).into_iter().collect()
+        }
+    }
+
+    /// The directories of crontabs to read
+    pub fn crontab_dirs(&self) -> Vec<PathBuf> {
+        self.crontab_dirs
+            .clone()
+            .unwrap_or_else(|| match self.system {
+                true => vec![PathBuf::from(system::SYSTEM_CRONTAB_DIR)],
+                false => vec![config_home().join("crust").join("cron.d")],
+            })
+    }
+
+    /// The spool directories to read, none unless in system mode
+    pub fn spool_dirs(&self) -> Vec<PathBuf> {
+        match (&self.spool_dirs, self.system) {
+            (Some(dirs), _) => dirs.clone(),
+            (None, true) => vec![PathBuf::from(system::SPOOL_DIR)],
+            (None, false) => Vec::new(),
+        }
+    }
+
+    /// Where the history is kept, the state directory of crust by default
+    /// and /var/lib/crust in system mode
+    pub fn log_dir(&self) -> PathBuf {
+        match (&self.log_dir, self.system) {
+            (Some(log_dir), _) => log_dir.clone(),
+            (None, true) => PathBuf::from(system::LOG_DIR),
+            (None, false) => state_dir(),
+        }
+    }
+
+    /// The variables every job starts with, including the shell and the
+    /// mail addresses
+    pub fn env(&self) -> Vec<(String, String)> {
+        let mut env: Vec<(String, String)> = self.env.clone().into_iter().collect();
+        if let Some(shell) = &self.shell {
+            env.push((String::from("SHELL"), shell.to_string_lossy().into_owned()));
+        }
+        if let Some(mail) = &self.mail {
+            for (name, value) in [("MAILTO", &mail.to), ("MAILFROM", &mail.from)].iter() {
+                if let Some(value) = value {
+                    env.push((name.to_string(), value.clone()));
+                }
+            }
+        }
+        env
+    }
+
+    /// Mails output only if the config has a [mail] section
+    pub fn mailer(&self) -> Mailer {
+        match &self.mail {
+            Some(mail) => Mailer::new(&mail.command),
+            None => Mailer::default(),
+        }
+    }
+}
+
+fn home() -> String {
+    std::env::var("HOME").unwrap_or_else(|_| String::from("/"))
+}
+
+/// `$XDG_CONFIG_HOME`, ~/.config if it is not set
+pub fn config_home() -> PathBuf {
+    std::env::var("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| Path::new(&home()).join(".config"))
+}
+
+/// `$XDG_STATE_HOME/crust`, where the history is kept by default
+pub fn state_dir() -> PathBuf {
+    let state_home = std::env::var("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| Path::new(&home()).join(".local/state"));
+    state_home.join("crust")
+}
+
+/// Expand a leading `~` and `$XDG_CONFIG_HOME` in `path`
+pub fn expand(path: &str) -> PathBuf {
+    let path = match path.strip_prefix('~') {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => format!("{}{}", home(), rest),
+        _ => path.to_owned(),
+    };
+    PathBuf::from(path.replace("$XDG_CONFIG_HOME", &config_home().to_string_lossy()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<Config, ConfigError> {
+        Config::parse(Path::new("config.toml"), text)
+    }
+
+    #[test]
+    fn settings() {
+        let config = parse(
+            "shell = \"/bin/sh\"\n\
+             history_retention = \"30d\"\n\
+             max_jobs = 4\n\
+             crontabs = [\"/nonexistent/crontab\", \"~/crontab\"]\n\
+             [env]\n\
+             PATH = \"/bin\"\n\
+             [group_limits]\n\
+             backup = 1\n\
+             [mail]\n\
+             to = \"ops@example.com\"\n",
+        )
+        .unwrap();
+        assert_eq!(config.history_retention, Some(Duration::days(30)));
+        assert_eq!(config.max_jobs, Some(4));
+        assert_eq!(config.group_limits["backup"], 1);
+        assert_eq!(config.crontabs[1], Path::new(&home()).join("crontab"));
+        assert_eq!(config.crontab(), Some(Path::new("/nonexistent/crontab")));
+        assert_eq!(
+            config.crontab_files(),
+            vec![PathBuf::from("/nonexistent/crontab")]
+        );
+        assert_eq!(
+            config.crontab_dirs(),
+            vec![config_home().join("crust/cron.d")]
+        );
+        assert_eq!(config.mail.as_ref().unwrap().command, "sendmail -t -i");
+        let env: Vec<(&str, &str)> = config
+            .env
+            .iter()
+            .map(|(n, v)| (n.as_str(), v.as_str()))
+            .collect();
+        assert_eq!(env, vec![("PATH", "/bin")]);
+        assert_eq!(config.env().len(), 3);
+
+        let empty = parse("").unwrap();
+        assert_eq!(empty.crontab(), None);
+        assert!(empty.crontab_files().is_empty());
+        assert!(empty.spool_dirs().is_empty());
+
+        let system = parse("system = true").unwrap();
+        assert_eq!(system.crontab_files(), vec![PathBuf::from("/etc/crontab")]);
+        assert_eq!(system.crontab_dirs(), vec![PathBuf::from("/etc/cron.d")]);
+        assert_eq!(
+            system.spool_dirs(),
+            vec![PathBuf::from("/var/spool/cron/crontabs")]
+        );
+        assert_eq!(system.log_dir(), PathBuf::from("/var/lib/crust"));
+        assert!(parse("crontab_dirs = []")
+            .unwrap()
+            .crontab_dirs()
+            .is_empty());
+        assert!(empty.env().is_empty());
+    }
+
+    #[test]
+    fn errors() {
+        let error = |text| parse(text).unwrap_err().to_string();
+        assert!(error("max_job = 4")
+            .starts_with("Error in config config.toml: unknown field `max_job`"));
+        assert!(error("timeout = \"soon\"").contains("invalid duration `soon`"));
+        assert_eq!(
+            error("max_jobs = 0"),
+            "Error in config config.toml: `max_jobs` must be at least 1"
+        );
+        assert_eq!(
+            error("[env]\n\"A B\" = \"c\""),
+            "Error in config config.toml: `A B` in [env] is not a variable name"
+        );
+        assert!(error("crontab_dirs = [\"/dev/null\"]").contains("other than a directory"));
+        assert!(error("spool_dirs = []").contains("needs `system = true`"));
+        assert!(error("shell = \"bash\"").contains("`shell` is not an absolute path"));
+        assert!(Config::load(Path::new("/nonexistent/config.toml")).is_err());
+    }
+}
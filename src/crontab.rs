@@ -0,0 +1,779 @@
+//! A lossless document model of a crontab.
+//!
+//! Every physical line is kept verbatim together with its line ending, so a
+//! document that is parsed and printed again is byte-identical to its input.
+//! Lines are classified and entry lines are split into spans, which lets
+//! callers edit a single entry without disturbing the rest of the file.
+//!
+//! Entries normally have five schedule fields. The `seconds(on)` option adds
+//! a leading seconds field, either for a single entry or, given on a `!`
+//! line, for all following entries. The `year(on)` option adds a trailing
+//! year field the same way, e.g. `2027` or `2025-2030`.
+//! `@every` takes the period and an optional `from` time as further fields.
+//! `@include path` lines name other crontabs, which the parser reads in their
+//! place.
+//!
+//! Every entry is a single line unless `multiline(on)` is given, again for a
+//! single entry or on a `!` line. Then an entry line ending in a backslash
+//! continues on the next line, and a command ending in `<<WORD` continues up
+//! to a line with just `WORD`. Such a line keeps its text verbatim for
+//! printing, while its spans refer to the joined text: continuations are
+//! removed with their backslash and the lines of a block are joined with
+//! `\n`.
+use std::fmt;
+
+/// A byte range into the text of a single line.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn of<'a>(&self, text: &'a str) -> &'a str {
+        &text[self.start..self.end]
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum LineKind {
+    Blank,
+    Comment,
+    /// `NAME=value`, the value span excludes surrounding quotes
+    Env { name: Span, value: Span },
+    /// `!option(value),...`, the span excludes the `!`
+    Defaults(Span),
+    /// `@include path`, the span of the path or glob
+    Include(Span),
+    Entry(EntryNode),
+}
+
+/// The spans of a schedule line, the schedule fields are the whitespace
+/// separated tokens in front of the command. Options are given in front of
+/// the schedule as `&option(value),...`, the span excludes the `&`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct EntryNode {
+    pub options: Option<Span>,
+    pub fields: Vec<Span>,
+    pub command: Span,
+    /// The first field is a seconds field
+    pub seconds: bool,
+    /// The last field is a year field
+    pub year: bool,
+    /// The entry may continue on the next lines
+    pub multiline: bool,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Line {
+    /// The joined text of a line that continues on further lines
+    text: String,
+    /// The verbatim text with the inner line endings, if it is not `text`
+    raw: Option<String>,
+    ending: String,
+    kind: LineKind,
+    // Whether entries on this line have a seconds or a year field or may
+    // continue unless they say otherwise
+    seconds: bool,
+    year: bool,
+    multiline: bool,
+}
+
+impl Line {
+    fn new(text: &str, ending: &str, seconds: bool, year: bool, multiline: bool) -> Line {
+        Line {
+            text: text.to_owned(),
+            raw: None,
+            ending: ending.to_owned(),
+            kind: classify(text, seconds, year, multiline),
+            seconds,
+            year,
+            multiline,
+        }
+    }
+
+    /// The text of the line, joined if it continues on further lines
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// How many lines of the file this line spans
+    pub fn line_count(&self) -> usize {
+        self.raw.as_deref().unwrap_or(&self.text).matches('\n').count() + 1
+    }
+
+    pub fn kind(&self) -> &LineKind {
+        &self.kind
+    }
+
+    /// Whether the schedule of an entry starts with a seconds field, and
+    /// whether the entries of an included crontab do unless they say
+    /// otherwise
+    pub fn has_seconds(&self) -> bool {
+        match &self.kind {
+            LineKind::Entry(node) => node.seconds,
+            LineKind::Include(_) => self.seconds,
+            _ => false,
+        }
+    }
+
+    /// Whether the schedule of an entry ends with a year field
+    pub fn has_year(&self) -> bool {
+        match &self.kind {
+            LineKind::Entry(node) => node.year,
+            _ => false,
+        }
+    }
+
+    pub fn is_entry(&self) -> bool {
+        matches!(self.kind, LineKind::Entry(_))
+    }
+
+    /// The schedule part of an entry, from the first to the last field
+    pub fn schedule(&self) -> Option<&str> {
+        match &self.kind {
+            LineKind::Entry(node) => {
+                let first = node.fields.first()?;
+                let last = node.fields.last()?;
+                Some(&self.text[first.start..last.end])
+            }
+            _ => None,
+        }
+    }
+
+    pub fn options(&self) -> Option<&str> {
+        match &self.kind {
+            LineKind::Entry(node) => node.options.map(|span| span.of(&self.text)),
+            _ => None,
+        }
+    }
+
+    pub fn command(&self) -> Option<&str> {
+        match &self.kind {
+            LineKind::Entry(node) => Some(node.command.of(&self.text)),
+            _ => None,
+        }
+    }
+
+    /// The `WORD` that ends the block of a multiline entry whose command
+    /// ends in `<<WORD`
+    pub fn block_end(&self) -> Option<&str> {
+        match &self.kind {
+            LineKind::Entry(node) if node.multiline => {
+                heredoc_word(node.command.of(&self.text).lines().next()?)
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether the next line of the file belongs to this one
+    fn continues(&self) -> bool {
+        match self.block_end() {
+            Some(word) => !self.text.lines().skip(1).any(|line| line.trim() == word),
+            None => {
+                matches!(&self.kind, LineKind::Entry(node) if node.multiline)
+                    && (self.text.len() - self.text.trim_end_matches('\\').len()) % 2 == 1
+            }
+        }
+    }
+
+    /// Append the next line of the file
+    fn extend(&mut self, text: &str, ending: &str) {
+        let mut raw = self.raw.take().unwrap_or_else(|| self.text.clone());
+        raw.push_str(&self.ending);
+        raw.push_str(text);
+        if self.block_end().is_some() {
+            self.text.push('\n');
+        } else {
+            self.text.pop();
+        }
+        self.text.push_str(text);
+        self.raw = Some(raw).filter(|raw| *raw != self.text);
+        self.ending = ending.to_owned();
+        self.kind = classify(&self.text, self.seconds, self.year, self.multiline);
+    }
+
+    /// Replace the command of an entry, keeping the schedule and whitespace
+    pub fn set_command(&mut self, cmd: &str) -> bool {
+        let span = match &self.kind {
+            LineKind::Entry(node) => node.command,
+            _ => return false,
+        };
+        if span.start == span.end {
+            self.text.insert(span.start, ' ');
+            self.text.insert_str(span.start + 1, cmd);
+        } else {
+            self.text.replace_range(span.start..span.end, cmd);
+        }
+        self.raw = None;
+        self.kind = classify(&self.text, self.seconds, self.year, self.multiline);
+        true
+    }
+
+    /// Whether entries after this line have a seconds field
+    fn seconds_after(&self) -> bool {
+        match &self.kind {
+            LineKind::Defaults(span) => {
+                switch_option(span.of(&self.text), "seconds").unwrap_or(self.seconds)
+            }
+            _ => self.seconds,
+        }
+    }
+
+    /// Whether entries after this line have a year field
+    fn year_after(&self) -> bool {
+        match &self.kind {
+            LineKind::Defaults(span) => {
+                switch_option(span.of(&self.text), "year").unwrap_or(self.year)
+            }
+            _ => self.year,
+        }
+    }
+
+    /// Whether entries after this line may continue on further lines
+    fn multiline_after(&self) -> bool {
+        match &self.kind {
+            LineKind::Defaults(span) => {
+                switch_option(span.of(&self.text), "multiline").unwrap_or(self.multiline)
+            }
+            _ => self.multiline,
+        }
+    }
+
+    /// Comment out an entry, keeping the line otherwise untouched. Every
+    /// line of a block is commented out, continued lines are joined.
+    pub fn disable(&mut self) -> bool {
+        if !self.is_entry() {
+            return false;
+        }
+        self.text = format!("#{}", self.text.replace('\n', "\n#"));
+        self.raw = None;
+        self.kind = LineKind::Comment;
+        true
+    }
+}
+
+/// A crontab document, see the module documentation.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Crontab {
+    lines: Vec<Line>,
+    seconds: bool,
+}
+
+impl Crontab {
+    pub fn parse(text: &str) -> Crontab {
+        Crontab::parse_with_seconds(text, false)
+    }
+
+    /// Parse a crontab whose entries have a seconds field from the start
+    pub fn parse_with_seconds(text: &str, seconds: bool) -> Crontab {
+        let mut crontab = Crontab {
+            lines: Vec::new(),
+            seconds,
+        };
+        let (mut seconds, mut year, mut multiline) = (seconds, false, false);
+        let mut physical = PhysicalLines(text);
+        while let Some((body, ending)) = physical.next() {
+            let mut line = Line::new(body, ending, seconds, year, multiline);
+            while line.continues() {
+                match physical.next() {
+                    Some((body, ending)) => line.extend(body, ending),
+                    None => break,
+                }
+            }
+            seconds = line.seconds_after();
+            year = line.year_after();
+            multiline = line.multiline_after();
+            crontab.lines.push(line);
+        }
+        crontab
+    }
+
+    pub fn lines(&self) -> &[Line] {
+        &self.lines
+    }
+
+    /// The lines with the index of the line of the file they start on
+    pub fn numbered_lines(&self) -> impl Iterator<Item = (usize, &Line)> {
+        self.lines.iter().scan(0, |number, line| {
+            let start = *number;
+            *number += line.line_count();
+            Some((start, line))
+        })
+    }
+
+    pub fn lines_mut(&mut self) -> &mut [Line] {
+        &mut self.lines
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &Line> {
+        self.lines.iter().filter(|line| line.is_entry())
+    }
+
+    /// Append a line at the end of the document, terminating the current last
+    /// line first if needed. Returns false if `text` is not a single entry.
+    pub fn push_entry(&mut self, text: &str) -> bool {
+        let seconds = self.lines.last().map_or(self.seconds, Line::seconds_after);
+        let year = self.lines.last().is_some_and(Line::year_after);
+        let multiline = self.lines.last().is_some_and(Line::multiline_after);
+        let line = Line::new(text, "\n", seconds, year, multiline);
+        if text.contains('\n') || !line.is_entry() {
+            return false;
+        }
+        if let Some(last) = self.lines.last_mut() {
+            if last.ending.is_empty() {
+                last.ending.push('\n');
+            }
+        }
+        self.lines.push(line);
+        true
+    }
+
+    /// Remove all entries matching `pred`, returns how many were removed
+    pub fn remove_entries<F: Fn(&Line) -> bool>(&mut self, pred: F) -> usize {
+        let before = self.lines.len();
+        self.lines.retain(|line| !(line.is_entry() && pred(line)));
+        before - self.lines.len()
+    }
+
+    /// Comment out all entries matching `pred`, returns how many were disabled
+    pub fn disable_entries<F: Fn(&Line) -> bool>(&mut self, pred: F) -> usize {
+        self.lines
+            .iter_mut()
+            .filter(|line| line.is_entry() && pred(line))
+            .map(|line| line.disable())
+            .filter(|disabled| *disabled)
+            .count()
+    }
+}
+
+impl fmt::Display for Crontab {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for line in &self.lines {
+            f.write_str(line.raw.as_ref().unwrap_or(&line.text))?;
+            f.write_str(&line.ending)?;
+        }
+        Ok(())
+    }
+}
+
+/// The lines of a text with their endings, which are kept apart
+struct PhysicalLines<'a>(&'a str);
+
+impl<'a> Iterator for PhysicalLines<'a> {
+    type Item = (&'a str, &'a str);
+
+    fn next(&mut self) -> Option<(&'a str, &'a str)> {
+        if self.0.is_empty() {
+            return None;
+        }
+        let (line, tail) = match self.0.find('\n') {
+            Some(index) => self.0.split_at(index + 1),
+            None => (self.0, ""),
+        };
+        self.0 = tail;
+        Some(if let Some(body) = line.strip_suffix("\r\n") {
+            (body, "\r\n")
+        } else if let Some(body) = line.strip_suffix('\n') {
+            (body, "\n")
+        } else {
+            (line, "")
+        })
+    }
+}
+
+/// The `WORD` of a command ending in `<<WORD`, which may be quoted and
+/// follow `<<-` like in a shell. Here-strings (`<<<`) are not blocks.
+pub(crate) fn heredoc_word(command: &str) -> Option<&str> {
+    let command = command.trim_end();
+    let start = command.rfind("<<")?;
+    if command[..start].ends_with('<') {
+        return None;
+    }
+    let word = command[start + 2..].trim_start_matches('-').trim_start();
+    let word = ['\'', '"']
+        .iter()
+        .find_map(|quote| word.strip_prefix(*quote)?.strip_suffix(*quote))
+        .unwrap_or(word);
+    let valid = !word.is_empty() && word.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    Some(word).filter(|_| valid)
+}
+
+/// Byte spans of all whitespace separated words in `text`
+fn words(text: &str) -> Vec<Span> {
+    let mut spans = Vec::new();
+    let mut start = None;
+    for (index, c) in text.char_indices() {
+        match (c.is_whitespace(), start) {
+            (false, None) => start = Some(index),
+            (true, Some(s)) => {
+                spans.push(Span { start: s, end: index });
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(s) = start {
+        spans.push(Span {
+            start: s,
+            end: text.len(),
+        });
+    }
+    spans
+}
+
+/// Split `option(value),...` into names and values, commas inside of the
+/// parentheses do not separate options
+pub fn split_options(text: &str) -> Vec<(&str, &str)> {
+    let mut depth = 0;
+    let mut start = 0;
+    let mut list = Vec::new();
+    for (index, c) in text.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                list.push(&text[start..index]);
+                start = index + 1;
+            }
+            _ => {}
+        }
+    }
+    list.push(&text[start..]);
+
+    list.into_iter()
+        .map(|option| match option.find('(') {
+            Some(index) if option.ends_with(')') => (&option[..index], &option[index + 1..option.len() - 1]),
+            _ => (option, ""),
+        })
+        .collect()
+}
+
+fn paren_depth(text: &str) -> i32 {
+    text.chars()
+        .map(|c| match c {
+            '(' => 1,
+            ')' => -1,
+            _ => 0,
+        })
+        .sum()
+}
+
+/// The value of the last `on`/`off` option `name` in `text`, if any
+fn switch_option(text: &str, name: &str) -> Option<bool> {
+    split_options(text)
+        .into_iter()
+        .filter(|(option, _)| *option == name)
+        .filter_map(|(_, value)| match value {
+            "on" => Some(true),
+            "off" => Some(false),
+            _ => None,
+        })
+        .next_back()
+}
+
+pub(crate) fn is_env_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {}
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn classify(text: &str, seconds: bool, year: bool, multiline: bool) -> LineKind {
+    let trimmed = text.trim_start();
+    if trimmed.trim_end().is_empty() {
+        return LineKind::Blank;
+    }
+    if trimmed.starts_with('#') {
+        return LineKind::Comment;
+    }
+
+    let offset = text.len() - trimmed.len();
+    if let Some(eq) = trimmed.find('=') {
+        let name = trimmed[..eq].trim_end();
+        if is_env_name(name) {
+            let value = &trimmed[eq + 1..];
+            let value_start = offset + eq + 1 + (value.len() - value.trim_start().len());
+            let mut value = Span {
+                start: value_start,
+                end: offset + trimmed.trim_end().len(),
+            };
+            let quoted = value.of(text);
+            if quoted.len() >= 2
+                && (quoted.starts_with('"') && quoted.ends_with('"')
+                    || quoted.starts_with('\'') && quoted.ends_with('\''))
+            {
+                value.start += 1;
+                value.end -= 1;
+            }
+            return LineKind::Env {
+                name: Span {
+                    start: offset,
+                    end: offset + name.len(),
+                },
+                value,
+            };
+        }
+    }
+
+    if let Some(path) = trimmed.strip_prefix("@include") {
+        if path.is_empty() || path.starts_with(char::is_whitespace) {
+            let end = offset + trimmed.trim_end().len();
+            let start = (text.len() - path.trim_start().len()).min(end);
+            return LineKind::Include(Span { start, end });
+        }
+    }
+
+    if trimmed.starts_with('!') {
+        return LineKind::Defaults(Span {
+            start: offset + 1,
+            end: offset + trimmed.trim_end().len(),
+        });
+    }
+
+    let mut words = words(text);
+    let options = if trimmed.starts_with('&') {
+        let mut span = words.remove(0);
+        // Option values may contain whitespace inside of the parentheses
+        while paren_depth(span.of(text)) > 0 && !words.is_empty() {
+            span.end = words.remove(0).end;
+        }
+        Some(Span {
+            start: span.start + 1,
+            end: span.end,
+        })
+    } else {
+        None
+    };
+    let schedule = words.first().map(|span| span.of(text)).unwrap_or_default();
+    let seconds = options
+        .and_then(|span| switch_option(span.of(text), "seconds"))
+        .unwrap_or(seconds);
+    let year = options
+        .and_then(|span| switch_option(span.of(text), "year"))
+        .unwrap_or(year);
+    let multiline = options
+        .and_then(|span| switch_option(span.of(text), "multiline"))
+        .unwrap_or(multiline);
+    let special = schedule.starts_with('@');
+    let seconds = seconds && !special;
+    let year = year && !special;
+    let mut field_count = if schedule == "@every" {
+        // `@every 90m` or `@every 90m from 00:15`
+        match words.get(2) {
+            Some(word) if word.of(text) == "from" => 4,
+            _ => 2,
+        }
+    } else if special {
+        1
+    } else if seconds {
+        6
+    } else {
+        5
+    };
+    if year {
+        field_count += 1;
+    }
+    let fields: Vec<Span> = words.iter().take(field_count).cloned().collect();
+    let command = match words.get(field_count) {
+        Some(first) => Span {
+            start: first.start,
+            end: text.trim_end().len(),
+        },
+        None => Span {
+            start: text.trim_end().len(),
+            end: text.trim_end().len(),
+        },
+    };
+    LineKind::Entry(EntryNode {
+        options,
+        fields,
+        command,
+        seconds,
+        year,
+        multiline,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CRONTAB: &str = "# m h dom mon dow command\r\n\
+                           SHELL = \"/bin/bash\"\n\
+                           \n   \t\n\
+                           */5  *\t* * *   echo  \"two  spaces\"   \n\
+                           @reboot   ./start.sh\n\
+                           !catchup(once)\n\
+                           &catchup(all),random_delay(5m) @daily   sync.sh\n\
+                           0 0 1 1 * no-newline";
+
+    #[test]
+    fn round_trip() {
+        let crontab = Crontab::parse(CRONTAB);
+        assert_eq!(crontab.to_string(), CRONTAB);
+        assert_eq!(crontab.lines().len(), 9);
+        assert_eq!(crontab.entries().count(), 4);
+    }
+
+    #[test]
+    fn classify_lines() {
+        let crontab = Crontab::parse(CRONTAB);
+        let lines = crontab.lines();
+        assert_eq!(lines[0].kind(), &LineKind::Comment);
+        match lines[1].kind() {
+            LineKind::Env { name, value } => {
+                assert_eq!(name.of(lines[1].text()), "SHELL");
+                assert_eq!(value.of(lines[1].text()), "/bin/bash");
+            }
+            kind => panic!("expected env, got {:?}", kind),
+        }
+        assert_eq!(lines[2].kind(), &LineKind::Blank);
+        assert_eq!(lines[3].kind(), &LineKind::Blank);
+        assert_eq!(lines[4].schedule(), Some("*/5  *\t* * *"));
+        assert_eq!(lines[4].command(), Some("echo  \"two  spaces\""));
+        assert_eq!(lines[5].schedule(), Some("@reboot"));
+        assert_eq!(lines[5].command(), Some("./start.sh"));
+        match lines[6].kind() {
+            LineKind::Defaults(span) => assert_eq!(span.of(lines[6].text()), "catchup(once)"),
+            kind => panic!("expected defaults, got {:?}", kind),
+        }
+        assert_eq!(lines[7].options(), Some("catchup(all),random_delay(5m)"));
+        assert_eq!(lines[7].schedule(), Some("@daily"));
+        assert_eq!(lines[7].command(), Some("sync.sh"));
+
+        let crontab = Crontab::parse("&after(test -e /x),group(a b) @reboot  a b");
+        let line = &crontab.lines()[0];
+        assert_eq!(line.options(), Some("after(test -e /x),group(a b)"));
+        assert_eq!(line.schedule(), Some("@reboot"));
+        assert_eq!(line.command(), Some("a b"));
+
+        let crontab = Crontab::parse(" @include  ../shared/*.cron \n@include\n@includes a\n");
+        let lines = crontab.lines();
+        assert_eq!(lines[0].kind(), &LineKind::Include(Span { start: 11, end: 27 }));
+        assert_eq!(lines[1].kind(), &LineKind::Include(Span { start: 8, end: 8 }));
+        assert_eq!(lines[2].schedule(), Some("@includes"));
+    }
+
+    #[test]
+    fn edits_keep_untouched_lines() {
+        let mut crontab = Crontab::parse(CRONTAB);
+        assert_eq!(crontab.disable_entries(|l| l.command() == Some("./start.sh")), 1);
+        assert_eq!(crontab.remove_entries(|l| l.command() == Some("no-newline")), 1);
+        assert!(crontab.push_entry("@daily backup"));
+        assert!(!crontab.push_entry("# not an entry"));
+        crontab.lines_mut()[4].set_command("true");
+
+        let expected = "# m h dom mon dow command\r\n\
+                        SHELL = \"/bin/bash\"\n\
+                        \n   \t\n\
+                        */5  *\t* * *   true   \n\
+                        #@reboot   ./start.sh\n\
+                        !catchup(once)\n\
+                        &catchup(all),random_delay(5m) @daily   sync.sh\n\
+                        @daily backup\n";
+        assert_eq!(crontab.to_string(), expected);
+    }
+
+    #[test]
+    fn seconds_field() {
+        let text = "* * * * * a b\n\
+                    !seconds(on)\n\
+                    */15 * * * * * a b\n\
+                    &seconds(off) * * * * * a b\n\
+                    @hourly a b\n";
+        let mut crontab = Crontab::parse(text);
+        let lines = crontab.lines();
+        assert_eq!(lines[0].command(), Some("a b"));
+        assert!(!lines[0].has_seconds());
+        assert_eq!(lines[2].schedule(), Some("*/15 * * * * *"));
+        assert_eq!(lines[2].command(), Some("a b"));
+        assert!(lines[2].has_seconds());
+        assert_eq!(lines[3].command(), Some("a b"));
+        assert_eq!(lines[4].command(), Some("a b"));
+
+        assert!(crontab.push_entry("0 * * * * * c"));
+        assert_eq!(crontab.lines()[5].command(), Some("c"));
+        let crontab = Crontab::parse_with_seconds("0 * * * * * c", true);
+        assert_eq!(crontab.lines()[0].command(), Some("c"));
+    }
+
+    #[test]
+    fn year_field() {
+        let crontab = Crontab::parse(
+            "0 5 * * * 2048 --run\n\
+             &year(on) 0 0 1 1 * 2027 a\n\
+             !year(on)\n\
+             0 0 1 1 * 2025-2027,2040 a\n\
+             &seconds(on) 0 0 0 1 1 * 2027 a\n\
+             @daily a\n\
+             &year(off) 0 5 * * * 2048 --run\n",
+        );
+        let lines = crontab.lines();
+        // Without the option a number after the schedule is a command
+        assert!(!lines[0].has_year());
+        assert_eq!(lines[0].command(), Some("2048 --run"));
+        assert_eq!(lines[1].schedule(), Some("0 0 1 1 * 2027"));
+        assert!(lines[1].has_year());
+        assert_eq!(lines[3].command(), Some("a"));
+        assert!(lines[4].has_seconds() && lines[4].has_year());
+        assert_eq!(lines[4].command(), Some("a"));
+        assert!(!lines[5].has_year());
+        assert_eq!(lines[6].command(), Some("2048 --run"));
+    }
+
+    #[test]
+    fn every_fields() {
+        let crontab = Crontab::parse("@every 90m a b\n@every 90m from 00:15 a b\n");
+        let lines = crontab.lines();
+        assert_eq!(lines[0].schedule(), Some("@every 90m"));
+        assert_eq!(lines[0].command(), Some("a b"));
+        assert_eq!(lines[1].schedule(), Some("@every 90m from 00:15"));
+        assert_eq!(lines[1].command(), Some("a b"));
+    }
+
+    #[test]
+    fn push_terminates_last_line() {
+        let mut crontab = Crontab::parse("@hourly a");
+        assert!(crontab.push_entry("@hourly b"));
+        assert_eq!(crontab.to_string(), "@hourly a\n@hourly b\n");
+    }
+
+    #[test]
+    fn multiline_entries() {
+        let text = "* * * * * a \\\n\
+                    # b\n\
+                    !multiline(on)\n\
+                    0 3 * * * tar czf /backup.tgz \\\r\n    /etc \\\n  /home\n\
+                    @daily <<END\necho one \\\ncd /\nEND\n\
+                    &multiline(off) @hourly c \\\n\
+                    @hourly d\n\
+                    @daily mysql <<'SQL'\nselect 1;\n  SQL\n\
+                    # comment \\\n\
+                    @weekly e <<<here\n";
+        let mut crontab = Crontab::parse(text);
+        assert_eq!(crontab.to_string(), text);
+        let lines = crontab.lines();
+        assert_eq!(lines.len(), 10);
+        assert_eq!(lines[0].command(), Some("a \\"));
+        assert_eq!(lines[3].command(), Some("tar czf /backup.tgz     /etc   /home"));
+        assert_eq!(lines[4].command(), Some("<<END\necho one \\\ncd /\nEND"));
+        assert_eq!(lines[4].block_end(), Some("END"));
+        assert_eq!(lines[5].command(), Some("c \\"));
+        assert_eq!(lines[7].block_end(), Some("SQL"));
+        assert_eq!(lines[8].kind(), &LineKind::Comment);
+        assert_eq!(lines[9].block_end(), None);
+        let starts: Vec<usize> = crontab.numbered_lines().map(|(start, _)| start).collect();
+        assert_eq!(starts, vec![0, 1, 2, 3, 6, 10, 11, 12, 15, 16]);
+
+        crontab.lines_mut()[3].set_command("true");
+        crontab.lines_mut()[4].disable();
+        let edited = crontab.to_string();
+        assert!(edited.contains("!multiline(on)\n0 3 * * * true\n#@daily <<END\n#echo one \\\n#cd /\n#END\n"));
+        let again = Crontab::parse(&edited);
+        assert_eq!(again.lines().len(), 13);
+        assert_eq!(again.entries().count(), 6);
+    }
+}
@@ -0,0 +1,589 @@
+//! A crontab entry and the times it runs at.
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use chrono::prelude::*;
+use chrono::Duration;
+
+use crate::expr::*;
+use crate::options::JobOptions;
+use crate::parser::{format_duration, job_hash};
+use crate::schedule::Schedule;
+use crate::system::User;
+
+/// What runs when an entry is due
+pub enum Job {
+    /// A command run by the shell of the entry, /bin/sh by default
+    Shell(String),
+    /// A closure run on a thread of its own, a panic counts as a failed run
+    Fn(Box<dyn Fn() + Send + Sync>),
+}
+
+impl fmt::Debug for Job {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Job::Shell(cmd) => f.debug_tuple("Shell").field(cmd).finish(),
+            Job::Fn(_) => f.write_str("Fn(..)"),
+        }
+    }
+}
+
+/// Where an entry was read from
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Source {
+    /// None if the crontab was not read from a file
+    pub path: Option<PathBuf>,
+    /// The line of the entry, counted from 1, 0 if it was not read from a
+    /// crontab
+    pub line: usize,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.path {
+            Some(path) => write!(f, "{}:{}", path.display(), self.line),
+            None => write!(f, "line {}", self.line),
+        }
+    }
+}
+
+/// A job and when it runs, as read from one line of a crontab
+#[derive(Clone, Debug)]
+pub struct CronEntry {
+    pub(crate) schedule: Schedule,
+    /// Set for `@every` entries, which ignore the schedule
+    pub(crate) every: Option<Every>,
+    /// Set for @reboot entries, how long to wait after crust started
+    pub(crate) startup: Option<Duration>,
+    /// The shell command, or the name of a closure job
+    pub(crate) cmd: String,
+    pub(crate) job: Arc<Job>,
+    pub(crate) env: Vec<(String, String)>,
+    pub(crate) options: JobOptions,
+    pub(crate) source: Source,
+    /// Who the job runs as, crust itself if None
+    pub(crate) user: Option<User>,
+}
+
+impl CronEntry {
+    pub fn new(cmd: &str, schedule: Schedule) -> CronEntry {
+        CronEntry {
+            schedule,
+            startup: None,
+            ..CronEntry::new_startup_task(cmd)
+        }
+    }
+
+    pub fn new_startup_task(cmd: &str) -> CronEntry {
+        CronEntry {
+            schedule: Schedule::default(),
+            every: None,
+            startup: Some(Duration::zero()),
+            cmd: cmd.to_owned(),
+            job: Arc::new(Job::Shell(cmd.to_owned())),
+            env: Vec::new(),
+            options: JobOptions::default(),
+            source: Source::default(),
+            user: None,
+        }
+    }
+
+    pub fn new_interval_task(cmd: &str, every: Every) -> CronEntry {
+        CronEntry {
+            every: Some(every),
+            startup: None,
+            ..CronEntry::new_startup_task(cmd)
+        }
+    }
+
+    /// Run `job` instead of the command, the entry keeps its name for the
+    /// history and for `CronScheduler::job`
+    pub fn set_job(&mut self, job: Job) {
+        self.job = Arc::new(job);
+    }
+
+    /// Run the job with these variables set, on top of the environment of crust
+    pub fn set_env(&mut self, env: Vec<(String, String)>) {
+        self.env = env;
+    }
+
+    pub fn set_options(&mut self, options: JobOptions) {
+        self.options = options;
+    }
+
+    /// The shell command of the job, or the name of a closure job, which
+    /// also identifies it
+    pub fn cmd(&self) -> &str {
+        &self.cmd
+    }
+
+    pub fn job(&self) -> &Job {
+        &self.job
+    }
+
+    pub fn env(&self) -> &[(String, String)] {
+        &self.env
+    }
+
+    /// The value of the variable `name`, the last one if it is set twice
+    pub fn var(&self, name: &str) -> Option<&str> {
+        self.env
+            .iter()
+            .rev()
+            .find(|(var, _)| var == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The shell that runs the command, `SHELL` or /bin/sh like in cron
+    pub fn shell(&self) -> &str {
+        self.var("SHELL").unwrap_or("/bin/sh")
+    }
+
+    pub fn options(&self) -> &JobOptions {
+        &self.options
+    }
+
+    pub fn source(&self) -> &Source {
+        &self.source
+    }
+
+    /// The user of an entry of a system crontab
+    pub fn user(&self) -> Option<&User> {
+        self.user.as_ref()
+    }
+
+    /// The job in the history and among the jobs of a scheduler: the
+    /// command, after the user for entries of system crontabs, so that the
+    /// same command of two users is kept apart
+    pub fn job_name(&self) -> String {
+        match &self.user {
+            Some(user) => format!("{}: {}", user.name, self.cmd),
+            None => self.cmd.clone(),
+        }
+    }
+
+    /// The time fields, unused by `@every` and @reboot entries
+    pub fn schedule(&self) -> &Schedule {
+        &self.schedule
+    }
+
+    pub fn every(&self) -> Option<&Every> {
+        self.every.as_ref()
+    }
+
+    /// How long an @reboot entry waits after crust started, None for others
+    pub fn startup(&self) -> Option<Duration> {
+        self.startup
+    }
+
+    /// Count the runs of an `@every` entry without a `from` time from `start`
+    pub fn set_last_start(&mut self, start: DateTime<Local>) {
+        if let Some(Every {
+            anchor: Anchor::LastStart(last),
+            ..
+        }) = &mut self.every
+        {
+            *last = Some(start.with_nanosecond(0).unwrap());
+        }
+    }
+
+    /// The first time at or after `now`, truncated to the second, that
+    /// matches the schedule. None once the last year of the schedule is over.
+    pub fn next_execution(&self, now: DateTime<Local>) -> Option<DateTime<Local>> {
+        match &self.every {
+            Some(every) => Some(every.next(now)),
+            None => self.schedule.next(now),
+        }
+    }
+
+    /// The last time at or before `now`, truncated to the second, that
+    /// matches the schedule. None before the first year of the schedule.
+    pub fn prev_execution(&self, now: DateTime<Local>) -> Option<DateTime<Local>> {
+        match &self.every {
+            Some(every) => Some(every.prev(now)),
+            None => self.schedule.prev(now),
+        }
+    }
+
+    /// Every run at or after `from`, computed as they are needed
+    pub fn iter_from(&self, from: DateTime<Local>) -> impl Iterator<Item = DateTime<Local>> + '_ {
+        let mut next = self.next_execution(from);
+        std::iter::from_fn(move || {
+            let run = next?;
+            next = self.next_execution(run + Duration::seconds(1));
+            Some(run)
+        })
+    }
+
+    /// Every run at or before `until`, the latest first, computed as they
+    /// are needed
+    pub fn iter_before(
+        &self,
+        until: DateTime<Local>,
+    ) -> impl Iterator<Item = DateTime<Local>> + '_ {
+        let mut prev = self.prev_execution(until);
+        std::iter::from_fn(move || {
+            let run = prev?;
+            prev = self.prev_execution(run - Duration::seconds(1));
+            Some(run)
+        })
+    }
+
+    /// Every run from `start` up to and including `end`, from either end
+    pub fn iter_between(&self, start: DateTime<Local>, end: DateTime<Local>) -> Runs<'_> {
+        Runs {
+            entry: self,
+            front: Some(start),
+            back: Some(end),
+        }
+    }
+
+    /// How long runs of this job are held back, see `JobOptions::start_delay`
+    pub fn start_delay(&self) -> Duration {
+        match self.options.start_delay {
+            Some(max) if max.num_milliseconds() > 0 => {
+                let hash = job_hash(&self.cmd, "start_delay");
+                Duration::milliseconds((hash % max.num_milliseconds() as u64) as i64)
+            }
+            _ => Duration::zero(),
+        }
+    }
+}
+
+/// The entry as a crontab line, preceded by a line for each of its
+/// variables. Closure jobs are written with their name as the command.
+impl fmt::Display for CronEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (name, value) in &self.env {
+            // The crontab strips one pair of quotes and surrounding spaces
+            let quoted = value.len() >= 2
+                && (value.starts_with('"') && value.ends_with('"')
+                    || value.starts_with('\'') && value.ends_with('\''));
+            if quoted || value.trim() != value {
+                writeln!(f, "{}=\"{}\"", name, value)?;
+            } else {
+                writeln!(f, "{}={}", name, value)?;
+            }
+        }
+        let mut options = self.options.to_string();
+        let seconds = self.every.is_none() && self.startup.is_none() && self.schedule.has_seconds();
+        if seconds {
+            if !options.is_empty() {
+                options.push(',');
+            }
+            options.push_str("seconds(on)");
+        }
+        if self.every.is_none() && self.startup.is_none() && self.schedule.has_year() {
+            if !options.is_empty() {
+                options.push(',');
+            }
+            options.push_str("year(on)");
+        }
+        // Scripts of several lines are written as a block
+        let block = self.cmd.contains('\n');
+        if block {
+            if !options.is_empty() {
+                options.push(',');
+            }
+            options.push_str("multiline(on)");
+        }
+        if !options.is_empty() {
+            write!(f, "&{} ", options)?;
+        }
+        match (&self.every, self.startup) {
+            (_, Some(delay)) if delay > Duration::zero() => {
+                write!(f, "@reboot+{}", format_duration(delay))?
+            }
+            (_, Some(_)) => f.write_str("@reboot")?,
+            (Some(every), None) => write!(f, "@every {}", every)?,
+            (None, None) => write!(f, "{}", self.schedule)?,
+        }
+        if block {
+            let mut word = String::from("END");
+            while self.cmd.lines().any(|line| line.trim() == word) {
+                word.push('_');
+            }
+            write!(f, " <<{}\n{}\n{}", word, self.cmd, word)
+        } else {
+            write!(f, " {}", self.cmd)
+        }
+    }
+}
+
+/// The runs of an entry between two times, see `CronEntry::iter_between`
+pub struct Runs<'a> {
+    entry: &'a CronEntry,
+    // Runs left to yield lie between these, None once they have crossed
+    front: Option<DateTime<Local>>,
+    back: Option<DateTime<Local>>,
+}
+
+impl Iterator for Runs<'_> {
+    type Item = DateTime<Local>;
+
+    fn next(&mut self) -> Option<DateTime<Local>> {
+        let back = self.back?;
+        let run = self
+            .entry
+            .next_execution(self.front?)
+            .filter(|run| *run <= back);
+        self.front = run.map(|run| run + Duration::seconds(1));
+        run
+    }
+}
+
+impl DoubleEndedIterator for Runs<'_> {
+    fn next_back(&mut self) -> Option<DateTime<Local>> {
+        let front = self.front?;
+        let run = self
+            .entry
+            .prev_execution(self.back?)
+            .filter(|run| *run >= front);
+        self.back = run.map(|run| run - Duration::seconds(1));
+        run
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_crontab;
+
+    fn entry(line: &str) -> CronEntry {
+        parse_crontab(line).unwrap().remove(0)
+    }
+
+    fn time(s: &str) -> DateTime<Local> {
+        Local
+            .from_local_datetime(&NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M").unwrap())
+            .unwrap()
+    }
+
+    #[test]
+    fn next_execution() {
+        let e = entry("*/15 * * * * cmd");
+        assert_eq!(
+            e.next_execution(time("2019-08-01 10:01")),
+            Some(time("2019-08-01 10:15"))
+        );
+        assert_eq!(
+            e.next_execution(time("2019-08-01 10:15")),
+            Some(time("2019-08-01 10:15"))
+        );
+        assert_eq!(
+            e.next_execution(time("2019-08-01 23:50")),
+            Some(time("2019-08-02 00:00"))
+        );
+
+        let e = entry("30 4 * * * cmd");
+        assert_eq!(
+            e.next_execution(time("2019-08-01 05:00")),
+            Some(time("2019-08-02 04:30"))
+        );
+
+        let e = entry("0 0 1 3,6 * cmd");
+        assert_eq!(
+            e.next_execution(time("2019-08-01 05:00")),
+            Some(time("2020-03-01 00:00"))
+        );
+
+        let e = entry("0 12 29 2 * cmd");
+        assert_eq!(
+            e.next_execution(time("2019-03-01 00:00")),
+            Some(time("2020-02-29 12:00"))
+        );
+
+        // Day of month or day of week when both are given
+        let e = entry("0 0 13 * 5 cmd");
+        assert_eq!(
+            e.next_execution(time("2019-08-01 12:00")),
+            Some(time("2019-08-02 00:00"))
+        );
+        assert_eq!(
+            e.next_execution(time("2019-08-12 12:00")),
+            Some(time("2019-08-13 00:00"))
+        );
+    }
+
+    #[test]
+    fn prev_execution() {
+        let e = entry("*/15 * * * * cmd");
+        assert_eq!(
+            e.prev_execution(time("2019-08-01 10:14")),
+            Some(time("2019-08-01 10:00"))
+        );
+        assert_eq!(
+            e.prev_execution(time("2019-08-01 10:15")),
+            Some(time("2019-08-01 10:15"))
+        );
+        assert_eq!(
+            e.prev_execution(time("2019-08-02 00:10")),
+            Some(time("2019-08-02 00:00"))
+        );
+
+        let e = entry("0 12 29 2 * cmd");
+        assert_eq!(
+            e.prev_execution(time("2020-02-01 00:00")),
+            Some(time("2016-02-29 12:00"))
+        );
+
+        let e = entry("0 0 13 * 5 cmd");
+        assert_eq!(
+            e.prev_execution(time("2019-08-12 12:00")),
+            Some(time("2019-08-09 00:00"))
+        );
+
+        let e = entry("&year(on) 0 0 1 1 * 2025 cmd");
+        assert_eq!(e.prev_execution(time("2024-06-01 00:00")), None);
+    }
+
+    #[test]
+    fn iterators() {
+        let e = entry("0 */6 * * * cmd");
+        let runs: Vec<_> = e.iter_from(time("2019-08-01 05:00")).take(3).collect();
+        let expected = vec![
+            time("2019-08-01 06:00"),
+            time("2019-08-01 12:00"),
+            time("2019-08-01 18:00"),
+        ];
+        assert_eq!(runs, expected);
+        let runs: Vec<_> = e.iter_before(time("2019-08-01 13:00")).take(3).collect();
+        let expected = vec![
+            time("2019-08-01 12:00"),
+            time("2019-08-01 06:00"),
+            time("2019-08-01 00:00"),
+        ];
+        assert_eq!(runs, expected);
+
+        let (start, end) = (time("2019-08-01 00:00"), time("2019-08-02 00:00"));
+        let runs: Vec<_> = e.iter_between(start, end).collect();
+        assert_eq!(runs.len(), 5);
+        let mut back: Vec<_> = e.iter_between(start, end).rev().collect();
+        back.reverse();
+        assert_eq!(runs, back);
+
+        // Both ends meet in the middle without yielding a run twice
+        let mut runs = e.iter_between(start, end);
+        assert_eq!(runs.next(), Some(time("2019-08-01 00:00")));
+        assert_eq!(runs.next_back(), Some(time("2019-08-02 00:00")));
+        assert_eq!(runs.next_back(), Some(time("2019-08-01 18:00")));
+        assert_eq!(runs.next(), Some(time("2019-08-01 06:00")));
+        assert_eq!(runs.next(), Some(time("2019-08-01 12:00")));
+        assert_eq!(runs.next_back(), None);
+        assert_eq!(runs.next(), None);
+
+        let e = entry("&year(on) 0 0 1 1 * 2025-2026 cmd");
+        assert_eq!(e.iter_from(time("2019-08-01 00:00")).count(), 2);
+        assert_eq!(e.iter_before(time("2030-01-01 00:00")).count(), 2);
+    }
+
+    #[test]
+    fn seconds() {
+        let at = |s: &str| {
+            Local
+                .from_local_datetime(
+                    &NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S").unwrap(),
+                )
+                .unwrap()
+        };
+        let e = entry("&seconds(on) */15 * * * * * cmd");
+        assert_eq!(
+            e.next_execution(at("2019-08-01 10:00:01")),
+            Some(at("2019-08-01 10:00:15"))
+        );
+        assert_eq!(
+            e.next_execution(at("2019-08-01 10:00:15")),
+            Some(at("2019-08-01 10:00:15"))
+        );
+        assert_eq!(
+            e.next_execution(at("2019-08-01 10:59:50")),
+            Some(at("2019-08-01 11:00:00"))
+        );
+
+        let e = entry("&seconds(on) 30 5 * * * * cmd");
+        assert_eq!(
+            e.next_execution(at("2019-08-01 10:05:31")),
+            Some(at("2019-08-01 11:05:30"))
+        );
+
+        let e = entry("* * * * * cmd");
+        assert_eq!(
+            e.next_execution(at("2019-08-01 10:00:01")),
+            Some(at("2019-08-01 10:01:00"))
+        );
+    }
+
+    #[test]
+    fn calendar_days() {
+        let e = entry("0 0 L * * cmd");
+        assert_eq!(
+            e.next_execution(time("2020-02-01 12:00")),
+            Some(time("2020-02-29 00:00"))
+        );
+        let e = entry("0 0 LW * * cmd");
+        assert_eq!(
+            e.next_execution(time("2019-08-01 12:00")),
+            Some(time("2019-08-30 00:00"))
+        );
+        let e = entry("0 0 15W * * cmd");
+        assert_eq!(
+            e.next_execution(time("2019-06-01 12:00")),
+            Some(time("2019-06-14 00:00"))
+        );
+        let e = entry("0 0 * * 5L cmd");
+        assert_eq!(
+            e.next_execution(time("2019-08-01 12:00")),
+            Some(time("2019-08-30 00:00"))
+        );
+        let e = entry("0 0 * * 1#2,3 cmd");
+        assert_eq!(
+            e.next_execution(time("2019-08-01 12:00")),
+            Some(time("2019-08-07 00:00"))
+        );
+        assert_eq!(
+            e.next_execution(time("2019-08-08 12:00")),
+            Some(time("2019-08-12 00:00"))
+        );
+    }
+
+    #[test]
+    fn every() {
+        let mut e = entry("@every 90m cmd");
+        e.set_last_start(time("2019-08-01 10:00"));
+        assert_eq!(
+            e.next_execution(time("2019-08-01 10:01")),
+            Some(time("2019-08-01 11:30"))
+        );
+
+        let e = entry("@every 1h from 00:15 cmd");
+        assert_eq!(
+            e.next_execution(time("2019-08-01 10:01")),
+            Some(time("2019-08-01 10:15"))
+        );
+    }
+
+    #[test]
+    fn year() {
+        let e = entry("&year(on) 0 0 1 1 * 2027,2029 cmd");
+        assert_eq!(
+            e.next_execution(time("2026-10-18 12:00")),
+            Some(time("2027-01-01 00:00"))
+        );
+        assert_eq!(
+            e.next_execution(time("2027-01-01 00:01")),
+            Some(time("2029-01-01 00:00"))
+        );
+        assert_eq!(e.next_execution(time("2029-01-01 00:01")), None);
+
+        // Never happens, as 2027 has no February 29th
+        let e = entry("&year(on) 0 0 29 2 * 2027 cmd");
+        assert_eq!(e.next_execution(time("2026-10-18 12:00")), None);
+
+        // Without the option a number after the schedule is a command
+        let e = entry("0 5 * * * 2048 --run");
+        assert_eq!(e.cmd(), "2048 --run");
+        assert!(!e.schedule.has_year());
+        assert_eq!(e.to_string(), "0 5 * * * 2048 --run");
+    }
+}
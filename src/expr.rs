@@ -1,79 +1,331 @@
-#[derive(Clone, Debug)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum CronUnit {
+  Second,
   Minute,
   Hour,
   DayOfMonth,
   Month,
   DayOfWeek,
+  Year,
 }
 
+use chrono::prelude::*;
+use chrono::Duration;
+use std::fmt;
+
+use crate::parser::format_duration;
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum CronInterval {
   Every,
-  Multiple(Vec<u32>),
+  Multiple(Bits),
+  /// Fixed values together with days that depend on the calendar of the
+  /// month, only used for the day of month and day of week
+  Calendar(Bits, Vec<DaySpec>),
+  /// Years are the only values that do not fit in a bitmask
+  Years(Vec<u32>),
 }
 
-#[derive(Clone, Debug)]
+/// A set of field values, value n is in the set if bit n is set. All units
+/// but the year have values below 64.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Bits(pub u64);
+
+impl Bits {
+  /// The values from `min` to `max`, inclusive
+  pub fn range(min: u32, max: u32) -> Bits {
+    if min > max || min > 63 {
+      return Bits(0);
+    }
+    let high = if max >= 63 { !0 } else { (1 << (max + 1)) - 1 };
+    Bits(high & !0 << min)
+  }
+
+  pub fn contains(self, n: u32) -> bool {
+    n < 64 && self.0 >> n & 1 == 1
+  }
+
+  pub fn insert(&mut self, n: u32) {
+    self.0 |= 1 << n;
+  }
+
+  pub fn is_empty(self) -> bool {
+    self.0 == 0
+  }
+
+  /// The smallest value at or above `n`
+  pub fn next(self, n: u32) -> Option<u32> {
+    if n > 63 {
+      return None;
+    }
+    match self.0 & !0 << n {
+      0 => None,
+      rest => Some(rest.trailing_zeros()),
+    }
+  }
+
+  /// The largest value at or below `n`
+  pub fn prev(self, n: u32) -> Option<u32> {
+    let n = std::cmp::min(n, 63);
+    match self.0 & !0 >> (63 - n) {
+      0 => None,
+      rest => Some(63 - rest.leading_zeros()),
+    }
+  }
+
+  pub fn first(self) -> Option<u32> {
+    self.next(0)
+  }
+
+  pub fn last(self) -> Option<u32> {
+    self.prev(63)
+  }
+
+  pub fn iter(self) -> impl Iterator<Item = u32> {
+    (0..64).filter(move |n| self.contains(*n))
+  }
+}
+
+impl std::iter::FromIterator<u32> for Bits {
+  fn from_iter<I: IntoIterator<Item = u32>>(values: I) -> Bits {
+    let mut bits = Bits::default();
+    for n in values {
+      bits.insert(n);
+    }
+    bits
+  }
+}
+
+/// A day given relative to the month it is in
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DaySpec {
+  /// `L`, the last day of the month
+  LastDay,
+  /// `LW`, the last weekday (Monday to Friday) of the month
+  LastWeekday,
+  /// `15W`, the weekday closest to the given day, within the same month
+  NearestWeekday(u32),
+  /// `5L`, the last given day of the week in the month
+  LastOf(u32),
+  /// `1#2`, the given day of the week, then which one of the month it is
+  Nth(u32, u32),
+}
+
+impl DaySpec {
+  pub fn matches(&self, date: NaiveDate) -> bool {
+    let last = last_day_of_month(date.year(), date.month());
+    let weekday = date.weekday().num_days_from_sunday();
+    match *self {
+      DaySpec::LastDay => date.day() == last,
+      DaySpec::LastWeekday => date.day() == nearest_weekday(date, last),
+      DaySpec::NearestWeekday(day) => day <= last && date.day() == nearest_weekday(date, day),
+      DaySpec::LastOf(dow) => weekday == dow && date.day() + 7 > last,
+      DaySpec::Nth(dow, n) => weekday == dow && (date.day() - 1) / 7 + 1 == n,
+    }
+  }
+}
+
+pub fn last_day_of_month(year: i32, month: u32) -> u32 {
+  let (year, month) = match month {
+    12 => (year + 1, 1),
+    month => (year, month + 1),
+  };
+  NaiveDate::from_ymd(year, month, 1).pred().day()
+}
+
+/// The weekday closest to `day` in the month of `date`, without leaving
+/// the month
+fn nearest_weekday(date: NaiveDate, day: u32) -> u32 {
+  let last = last_day_of_month(date.year(), date.month());
+  match date.with_day(day).unwrap().weekday() {
+    Weekday::Sat if day == 1 => 3,
+    Weekday::Sat => day - 1,
+    Weekday::Sun if day == last => day - 2,
+    Weekday::Sun => day + 1,
+    _ => day,
+  }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct CronExpr(pub CronUnit, pub CronInterval);
 
+/// `@every 90m`, optionally `from 00:15`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Every {
+  pub period: Duration,
+  pub anchor: Anchor,
+}
+
+/// Where the runs of an `@every` entry are counted from
+#[derive(Clone, Debug, PartialEq)]
+pub enum Anchor {
+  /// The last start of the job, None until it is known
+  LastStart(Option<DateTime<Local>>),
+  /// This time of day, starting over every day
+  Daily(NaiveTime),
+}
+
+impl Every {
+  /// The first run at or after `now`, truncated to the second
+  pub fn next(&self, now: DateTime<Local>) -> DateTime<Local> {
+    let now = now.with_nanosecond(0).unwrap();
+    match self.anchor {
+      Anchor::LastStart(None) => now,
+      Anchor::LastStart(Some(start)) => start + self.periods(start, now, true),
+      Anchor::Daily(time) => {
+        let mut now = now.naive_local();
+        let mut day = now.date().pred();
+        loop {
+          let start = day.and_time(time);
+          let end = start + Duration::days(1);
+          let t = start + self.periods(start, now, true);
+          if t >= end {
+            day = day.succ();
+            continue;
+          }
+          // Skip times that do not exist because of a DST change
+          match Local.from_local_datetime(&t).earliest() {
+            Some(next) => return next,
+            None => now = t + Duration::seconds(1),
+          }
+        }
+      }
+    }
+  }
+
+  /// The last run at or before `now`, truncated to the second
+  pub fn prev(&self, now: DateTime<Local>) -> DateTime<Local> {
+    let now = now.with_nanosecond(0).unwrap();
+    match self.anchor {
+      Anchor::LastStart(None) => now,
+      Anchor::LastStart(Some(start)) => start + self.periods(start, now, false),
+      Anchor::Daily(time) => {
+        let mut now = now.naive_local();
+        loop {
+          let mut start = now.date().and_time(time);
+          if now < start {
+            start -= Duration::days(1);
+          }
+          let t = start + self.periods(start, now, false);
+          match Local.from_local_datetime(&t).earliest() {
+            Some(prev) => return prev,
+            None => now = t - Duration::seconds(1),
+          }
+        }
+      }
+    }
+  }
+
+  /// The whole periods to add to `start` to get to `now`, rounded up or
+  /// down to the runs around it
+  fn periods<T>(&self, start: T, now: T, round_up: bool) -> Duration
+  where
+    T: std::ops::Sub<T, Output = Duration>,
+  {
+    let elapsed = (now - start).num_milliseconds();
+    let period = self.period.num_milliseconds();
+    let mut periods = elapsed.div_euclid(period);
+    if round_up && elapsed.rem_euclid(period) != 0 {
+      periods += 1;
+    }
+    Duration::milliseconds(periods * period)
+  }
+}
+
 impl CronUnit {
   pub fn max(&self) -> u32 {
     use CronUnit::*;
     match self {
+      Second => 59,
       Minute => 59,
       Hour => 23,
       DayOfMonth => 31,
       Month => 12,
       DayOfWeek => 6,
+      // There is no real end, but the values of a field are listed
+      Year => 9999,
     }
   }
   pub fn min(&self) -> u32 {
     use CronUnit::*;
     match self {
+      Second => 0,
       Minute => 0,
       Hour => 0,
       DayOfMonth => 1,
       Month => 1,
       DayOfWeek => 0,
+      Year => 1970,
     }
   }
   pub fn bounds(&self) -> (u32, u32) {
     (self.min(), self.max())
   }
+  /// The name of the unit as used in configs, "day_of_month" for example
+  pub fn name(&self) -> &'static str {
+    use CronUnit::*;
+    match self {
+      Second => "second",
+      Minute => "minute",
+      Hour => "hour",
+      DayOfMonth => "day_of_month",
+      Month => "month",
+      DayOfWeek => "day_of_week",
+      Year => "year",
+    }
+  }
 }
 
 impl CronExpr {
+  /// The values of the field as a bitmask, empty for years
+  pub fn bits(&self) -> Bits {
+    let CronExpr(unit, interval) = self;
+    match interval {
+      CronInterval::Every => Bits::range(unit.min(), unit.max()),
+      CronInterval::Multiple(bits) | CronInterval::Calendar(bits, _) => *bits,
+      CronInterval::Years(_) => Bits::default(),
+    }
+  }
+
   pub fn contains(&self, now: u32) -> bool {
     let CronExpr(_, interval) = self;
     match interval {
       CronInterval::Every => true,
-      CronInterval::Multiple(v) => v.contains(&now),
+      CronInterval::Multiple(bits) | CronInterval::Calendar(bits, _) => bits.contains(now),
+      CronInterval::Years(years) => years.binary_search(&now).is_ok(),
     }
   }
-  pub fn next_from(&self, now: u32) -> u32 {
+
+  /// Whether the day of month or day of week of `date` is in the field
+  pub fn contains_day(&self, date: NaiveDate) -> bool {
     let CronExpr(unit, interval) = self;
+    let value = match unit {
+      CronUnit::DayOfWeek => date.weekday().num_days_from_sunday(),
+      _ => date.day(),
+    };
     match interval {
-      CronInterval::Every => {
-        let n = (now + 1) % (unit.max() + 1);
-        if n == 0 {
-          return n + unit.min();
-        }
-        return n;
-      }
-      CronInterval::Multiple(v) => {
-        if let Some(target) = v.iter().filter(|x| x > &&now).nth(0) {
-          return *target;
-        }
-        return *v.iter().nth(0).unwrap();
+      CronInterval::Calendar(_, specs) => {
+        self.contains(value) || specs.iter().any(|spec| spec.matches(date))
       }
+      _ => self.contains(value),
+    }
+  }
+  /// The first value after `now`, wrapping around to the first value
+  pub fn next_from(&self, now: u32) -> u32 {
+    let CronExpr(unit, interval) = self;
+    if let CronInterval::Years(years) = interval {
+      let next = years.iter().find(|year| **year > now).or_else(|| years.first());
+      return next.cloned().unwrap_or_else(|| unit.min());
     }
+    let bits = self.bits();
+    bits
+      .next(now + 1)
+      .or_else(|| bits.first())
+      .unwrap_or_else(|| unit.min())
   }
 
   pub fn is_every(&self) -> bool {
-    match self {
-      CronExpr(_, CronInterval::Every) => true,
-      _ => false,
-    }
+    matches!(self, CronExpr(_, CronInterval::Every))
   }
 
   pub fn is_multiple(&self) -> bool {
@@ -81,6 +333,86 @@ impl CronExpr {
   }
 }
 
+impl fmt::Display for CronUnit {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str(self.name())
+  }
+}
+
+impl fmt::Display for DaySpec {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      DaySpec::LastDay => f.write_str("L"),
+      DaySpec::LastWeekday => f.write_str("LW"),
+      DaySpec::NearestWeekday(day) => write!(f, "{}W", day),
+      DaySpec::LastOf(dow) => write!(f, "{}L", dow),
+      DaySpec::Nth(dow, n) => write!(f, "{}#{}", dow, n),
+    }
+  }
+}
+
+/// Write `values` as a field, runs of three or more values as a range
+fn write_values<I: Iterator<Item = u32>>(f: &mut fmt::Formatter, values: I) -> fmt::Result {
+  let mut runs: Vec<(u32, u32)> = Vec::new();
+  for n in values {
+    match runs.last_mut() {
+      Some((_, end)) if *end + 1 == n => *end = n,
+      _ => runs.push((n, n)),
+    }
+  }
+  for (i, (start, end)) in runs.into_iter().enumerate() {
+    if i > 0 {
+      f.write_str(",")?;
+    }
+    match end - start {
+      0 => write!(f, "{}", start)?,
+      1 => write!(f, "{},{}", start, end)?,
+      _ => write!(f, "{}-{}", start, end)?,
+    }
+  }
+  Ok(())
+}
+
+/// The field as it is written in a crontab, with the values listed out so
+/// that it reads back the same for every unit
+impl fmt::Display for CronInterval {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      CronInterval::Every => f.write_str("*"),
+      CronInterval::Multiple(bits) => write_values(f, bits.iter()),
+      CronInterval::Calendar(bits, specs) => {
+        write_values(f, bits.iter())?;
+        for (i, spec) in specs.iter().enumerate() {
+          if i > 0 || !bits.is_empty() {
+            f.write_str(",")?;
+          }
+          write!(f, "{}", spec)?;
+        }
+        Ok(())
+      }
+      CronInterval::Years(years) => write_values(f, years.iter().cloned()),
+    }
+  }
+}
+
+/// The part after `@every`, "1h30m" or "1h30m from 00:15"
+impl fmt::Display for Every {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str(&format_duration(self.period))?;
+    if let Anchor::Daily(time) = self.anchor {
+      write!(f, " from {}", time.format("%H:%M"))?;
+    }
+    Ok(())
+  }
+}
+
+/// The unit and the field, "minute 0,30" for example
+impl fmt::Display for CronExpr {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{} {}", self.0, self.1)
+  }
+}
+
 
 #[cfg(test)]
 mod tests {
@@ -88,7 +420,7 @@ mod tests {
   #[test]
   fn contains() {
     let v = vec![5, 15, 20];
-    let ce = CronExpr(CronUnit::Minute, CronInterval::Multiple(v));
+    let ce = CronExpr(CronUnit::Minute, CronInterval::Multiple(v.into_iter().collect()));
     assert!(ce.contains(5));
     assert!(ce.contains(15));
     assert!(ce.contains(20));
@@ -100,7 +432,7 @@ mod tests {
   #[test]
   fn next_from() {
     let v = vec![5, 15, 20];
-    let ce = CronExpr(CronUnit::Minute, CronInterval::Multiple(v));
+    let ce = CronExpr(CronUnit::Minute, CronInterval::Multiple(v.into_iter().collect()));
     assert_eq!(ce.next_from(5), 15);
     assert_eq!(ce.next_from(14), 15);
     assert_eq!(ce.next_from(25), 5);
@@ -115,5 +447,83 @@ mod tests {
     assert_eq!(ce.next_from(5), 6);
     assert_eq!(ce.next_from(1), 2);
     assert_eq!(ce.next_from(12), 1);
+
+    let ce = CronExpr(CronUnit::Year, CronInterval::Years(vec![2027, 2029]));
+    assert_eq!(ce.next_from(2027), 2029);
+    assert_eq!(ce.next_from(2030), 2027);
+  }
+
+  #[test]
+  fn bits() {
+    let bits: Bits = vec![0, 5, 15, 63].into_iter().collect();
+    assert!(bits.contains(63) && !bits.contains(64) && !bits.contains(6));
+    assert_eq!(bits.next(0), Some(0));
+    assert_eq!(bits.next(6), Some(15));
+    assert_eq!(bits.next(64), None);
+    assert_eq!(bits.prev(14), Some(5));
+    assert_eq!(bits.prev(100), Some(63));
+    assert_eq!(bits.first(), Some(0));
+    assert_eq!(bits.last(), Some(63));
+    assert_eq!(bits.iter().collect::<Vec<_>>(), vec![0, 5, 15, 63]);
+    assert_eq!(Bits::default().next(0), None);
+    assert_eq!(Bits::default().prev(10), None);
+
+    assert_eq!(Bits::range(1, 12).iter().collect::<Vec<_>>(), (1..=12).collect::<Vec<_>>());
+    assert_eq!(Bits::range(0, 63).0, !0);
+    assert!(Bits::range(5, 4).is_empty());
+  }
+
+  #[test]
+  fn every() {
+    let time = |s| Local.from_local_datetime(&NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M").unwrap()).unwrap();
+    let every = Every {
+      period: Duration::minutes(90),
+      anchor: Anchor::LastStart(Some(time("2019-08-01 10:00"))),
+    };
+    assert_eq!(every.next(time("2019-08-01 09:00")), time("2019-08-01 10:00"));
+    assert_eq!(every.next(time("2019-08-01 08:00")), time("2019-08-01 08:30"));
+    assert_eq!(every.prev(time("2019-08-01 08:00")), time("2019-08-01 07:00"));
+    assert_eq!(every.prev(time("2019-08-01 11:29")), time("2019-08-01 10:00"));
+    assert_eq!(every.prev(time("2019-08-01 11:30")), time("2019-08-01 11:30"));
+    assert_eq!(every.next(time("2019-08-01 10:00")), time("2019-08-01 10:00"));
+    assert_eq!(every.next(time("2019-08-01 10:01")), time("2019-08-01 11:30"));
+    assert_eq!(every.next(time("2019-08-02 10:01")), time("2019-08-02 11:30"));
+
+    let every = Every {
+      period: Duration::minutes(100),
+      anchor: Anchor::Daily(NaiveTime::from_hms(0, 15, 0)),
+    };
+    assert_eq!(every.next(time("2019-08-01 00:00")), time("2019-08-01 00:15"));
+    assert_eq!(every.next(time("2019-08-01 00:16")), time("2019-08-01 01:55"));
+    // The last run of the day is at 23:35, the next day starts over
+    assert_eq!(every.next(time("2019-08-01 23:36")), time("2019-08-02 00:15"));
+    assert_eq!(every.prev(time("2019-08-02 00:14")), time("2019-08-01 23:35"));
+    assert_eq!(every.prev(time("2019-08-01 01:56")), time("2019-08-01 01:55"));
+  }
+
+  #[test]
+  fn calendar_days() {
+    let date = |y, m, d| NaiveDate::from_ymd(y, m, d);
+    assert!(DaySpec::LastDay.matches(date(2020, 2, 29)));
+    assert!(!DaySpec::LastDay.matches(date(2020, 2, 28)));
+    // August 31st 2019 is a Saturday
+    assert!(DaySpec::LastWeekday.matches(date(2019, 8, 30)));
+    assert!(!DaySpec::LastWeekday.matches(date(2019, 8, 31)));
+    // June 15th 2019 is a Saturday, June 1st too
+    assert!(DaySpec::NearestWeekday(15).matches(date(2019, 6, 14)));
+    assert!(DaySpec::NearestWeekday(1).matches(date(2019, 6, 3)));
+    // September 1st 2019 is a Sunday, June 30th too
+    assert!(DaySpec::NearestWeekday(1).matches(date(2019, 9, 2)));
+    assert!(DaySpec::NearestWeekday(30).matches(date(2019, 6, 28)));
+    assert!(!DaySpec::NearestWeekday(31).matches(date(2019, 6, 28)));
+    assert!(DaySpec::LastOf(5).matches(date(2019, 8, 30)));
+    assert!(!DaySpec::LastOf(5).matches(date(2019, 8, 23)));
+    assert!(DaySpec::Nth(1, 2).matches(date(2019, 8, 12)));
+    assert!(!DaySpec::Nth(1, 2).matches(date(2019, 8, 5)));
+
+    let ce = CronExpr(CronUnit::DayOfMonth, CronInterval::Calendar(Bits(1 << 1), vec![DaySpec::LastDay]));
+    assert!(ce.contains_day(date(2019, 8, 1)));
+    assert!(ce.contains_day(date(2019, 8, 31)));
+    assert!(!ce.contains_day(date(2019, 8, 30)));
   }
 }
\ No newline at end of file
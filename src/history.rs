@@ -0,0 +1,300 @@
+//! Append-only run history, stored as one JSON record per line, the time
+//! each job last ran and the boot @reboot jobs last ran in.
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Record {
+    pub job: String,
+    pub scheduled: DateTime<Local>,
+    pub start: DateTime<Local>,
+    pub end: DateTime<Local>,
+    /// None if the job was killed by a signal or could not be started
+    pub exit_code: Option<i32>,
+    pub signal: Option<i32>,
+    pub output_path: Option<PathBuf>,
+    pub output_size: u64,
+    #[serde(default)]
+    pub timed_out: bool,
+    /// Counts up from 1 when a failed run is retried
+    #[serde(default = "first_attempt")]
+    pub attempt: u32,
+    /// How long the run waited for a free slot under the concurrency limits
+    #[serde(default)]
+    pub queue_wait_ms: u64,
+}
+
+fn first_attempt() -> u32 {
+    1
+}
+
+impl Record {
+    pub fn failed(&self) -> bool {
+        self.timed_out || self.exit_code != Some(0)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Filter {
+    pub job: Option<String>,
+    pub since: Option<DateTime<Local>>,
+    pub until: Option<DateTime<Local>>,
+    pub failed_only: bool,
+}
+
+impl Filter {
+    pub fn matches(&self, record: &Record) -> bool {
+        if let Some(job) = &self.job {
+            if !record.job.contains(job.as_str()) {
+                return false;
+            }
+        }
+        if let Some(since) = self.since {
+            if record.start < since {
+                return false;
+            }
+        }
+        if let Some(until) = self.until {
+            if record.start > until {
+                return false;
+            }
+        }
+        !self.failed_only || record.failed()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct History {
+    dir: PathBuf,
+    // Serializes updates of the last run file between job threads
+    last_run_lock: Arc<Mutex<()>>,
+    // Keeps records from being appended while old ones are pruned
+    records_lock: Arc<Mutex<()>>,
+}
+
+impl History {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> History {
+        History {
+            dir: dir.into(),
+            last_run_lock: Arc::new(Mutex::new(())),
+            records_lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    pub fn path(&self) -> PathBuf {
+        self.dir.join("history.jsonl")
+    }
+
+    /// Create a new file to capture the output of a run in
+    pub fn output_file(&self, start: &DateTime<Local>) -> io::Result<(PathBuf, File)> {
+        let dir = self.dir.join("output");
+        fs::create_dir_all(&dir)?;
+        let mut n = 0;
+        loop {
+            let name = format!("{}-{}.log", start.format("%Y%m%dT%H%M%S%.f"), n);
+            let path = dir.join(name);
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => return Ok((path, file)),
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => n += 1,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    pub fn append(&self, record: &Record) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+        let _guard = self.records_lock.lock().unwrap();
+        // A single write to a file opened for appending keeps concurrent
+        // writers from interleaving records
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.path())?
+            .write_all(line.as_bytes())
+    }
+
+    pub fn query(&self, filter: &Filter) -> io::Result<Vec<Record>> {
+        read_records(&self.path(), filter)
+    }
+
+    /// Drop the records of runs started before `before`, along with their
+    /// output. The number of records dropped.
+    pub fn prune(&self, before: DateTime<Local>) -> io::Result<usize> {
+        let _guard = self.records_lock.lock().unwrap();
+        let records = read_records(&self.path(), &Filter::default())?;
+        let (old, kept): (Vec<Record>, Vec<Record>) =
+            records.into_iter().partition(|record| record.start < before);
+        if old.is_empty() {
+            return Ok(0);
+        }
+        let mut text = String::new();
+        for record in &kept {
+            text.push_str(&serde_json::to_string(record)?);
+            text.push('\n');
+        }
+        let tmp = self.dir.join("history.jsonl.tmp");
+        fs::write(&tmp, text)?;
+        fs::rename(tmp, self.path())?;
+        for path in old.iter().filter_map(|record| record.output_path.as_ref()) {
+            // Output files may have been cleaned up by hand
+            let _ = fs::remove_file(path);
+        }
+        Ok(old.len())
+    }
+
+    /// The scheduled time of the last run of `job`
+    pub fn last_run(&self, job: &str) -> Option<DateTime<Local>> {
+        let _guard = self.last_run_lock.lock().unwrap();
+        self.last_runs().remove(job)
+    }
+
+    pub fn set_last_run(&self, job: &str, time: DateTime<Local>) -> io::Result<()> {
+        let _guard = self.last_run_lock.lock().unwrap();
+        let mut last_runs = self.last_runs();
+        last_runs.insert(job.to_owned(), time);
+        self.write_map("last-run.json", &last_runs)
+    }
+
+    fn last_runs(&self) -> HashMap<String, DateTime<Local>> {
+        self.read_map("last-run.json")
+    }
+
+    /// The boot id of the boot `job` last ran in as an @reboot job
+    pub fn boot_run(&self, job: &str) -> Option<String> {
+        let _guard = self.last_run_lock.lock().unwrap();
+        self.read_map::<String>("boot-run.json").remove(job)
+    }
+
+    pub fn set_boot_run(&self, job: &str, boot_id: &str) -> io::Result<()> {
+        let _guard = self.last_run_lock.lock().unwrap();
+        let mut boot_runs = self.read_map::<String>("boot-run.json");
+        boot_runs.insert(job.to_owned(), boot_id.to_owned());
+        self.write_map("boot-run.json", &boot_runs)
+    }
+
+    fn read_map<T: serde::de::DeserializeOwned>(&self, name: &str) -> HashMap<String, T> {
+        fs::read(self.dir.join(name))
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .unwrap_or_default()
+    }
+
+    fn write_map<T: Serialize>(&self, name: &str, map: &HashMap<String, T>) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        // Write to a temporary file first so a crash never leaves a
+        // truncated file behind
+        let path = self.dir.join(name);
+        let tmp = self.dir.join(format!("{}.tmp", name));
+        fs::write(&tmp, serde_json::to_vec(map)?)?;
+        fs::rename(tmp, path)
+    }
+}
+
+/// The id the kernel gives the running boot, None where it has none
+pub fn boot_id() -> Option<String> {
+    let id = fs::read_to_string("/proc/sys/kernel/random/boot_id").ok()?;
+    Some(id.trim().to_owned()).filter(|id| !id.is_empty())
+}
+
+fn read_records(path: &Path, filter: &Filter) -> io::Result<Vec<Record>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut records = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        // Skip records that were cut short, e.g. by a full disk
+        if let Ok(record) = serde_json::from_str::<Record>(&line) {
+            if filter.matches(&record) {
+                records.push(record);
+            }
+        }
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    use chrono::Duration;
+
+    fn record(job: &str, start: DateTime<Local>, exit_code: Option<i32>) -> Record {
+        Record {
+            job: job.to_owned(),
+            scheduled: start,
+            start,
+            end: start + Duration::seconds(1),
+            exit_code,
+            signal: None,
+            output_path: None,
+            output_size: 0,
+            timed_out: false,
+            attempt: 1,
+            queue_wait_ms: 0,
+        }
+    }
+
+    #[test]
+    fn append_and_query() {
+        let dir = TempDir::new("history");
+        let history = History::new(dir.path());
+        let now = Local::now().with_nanosecond(0).unwrap();
+        let old = record("backup.sh", now - Duration::days(2), Some(0));
+        let failed = record("backup.sh", now, Some(1));
+        let other = record("sync.sh", now, None);
+        for r in &[&old, &failed, &other] {
+            history.append(r).unwrap();
+        }
+
+        let all = history.query(&Filter::default()).unwrap();
+        assert_eq!(all, vec![old.clone(), failed.clone(), other.clone()]);
+
+        let filter = Filter {
+            job: Some("backup".to_owned()),
+            since: Some(now - Duration::days(1)),
+            ..Filter::default()
+        };
+        assert_eq!(history.query(&filter).unwrap(), vec![failed.clone()]);
+
+        let filter = Filter {
+            failed_only: true,
+            ..Filter::default()
+        };
+        assert_eq!(history.query(&filter).unwrap(), vec![failed.clone(), other.clone()]);
+
+        assert_eq!(history.prune(now - Duration::days(1)).unwrap(), 1);
+        assert_eq!(history.prune(now - Duration::days(1)).unwrap(), 0);
+        assert_eq!(history.query(&Filter::default()).unwrap(), vec![failed, other]);
+    }
+
+    #[test]
+    fn last_run() {
+        let dir = TempDir::new("last-run");
+        let history = History::new(dir.path());
+        let now = Local::now();
+        assert_eq!(history.last_run("backup.sh"), None);
+        history.set_last_run("backup.sh", now).unwrap();
+        history.set_last_run("sync.sh", now - Duration::hours(1)).unwrap();
+        assert_eq!(history.last_run("backup.sh"), Some(now));
+        assert_eq!(history.last_run("sync.sh"), Some(now - Duration::hours(1)));
+
+        assert_eq!(history.boot_run("start.sh"), None);
+        history.set_boot_run("start.sh", "1234").unwrap();
+        assert_eq!(history.boot_run("start.sh"), Some(String::from("1234")));
+        assert_eq!(history.last_run("backup.sh"), Some(now));
+    }
+}
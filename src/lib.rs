@@ -0,0 +1,72 @@
+//! The cron semantics of crust, for the daemon and for embedding it.
+//!
+//! Crontabs are parsed into [`CronEntry`]s by [`parser::parse_crontab`], an
+//! entry knows the times it runs at, and a [`CronScheduler`] runs the jobs
+//! of a crontab file.
+//!
+//! ```
+//! use chrono::prelude::*;
+//!
+//! let entries = crust::parser::parse_crontab("*/15 * * * * backup.sh").unwrap();
+//! let now = Local::now();
+//! let next = entries[0].next_execution(now).unwrap();
+//! assert!(next >= now.with_nanosecond(0).unwrap());
+//! assert_eq!(next.minute() % 15, 0);
+//! ```
+use std::fmt;
+use std::io;
+
+mod clock;
+pub mod config;
+pub mod crontab;
+pub mod entry;
+pub mod expr;
+pub mod history;
+pub mod limits;
+pub mod mail;
+pub mod options;
+pub mod parser;
+mod runner;
+pub mod schedule;
+pub mod scheduler;
+pub mod sources;
+pub mod system;
+#[cfg(test)]
+mod testing;
+#[cfg(feature = "serde")]
+mod serialize;
+#[cfg(feature = "tokio")]
+pub mod tokio_scheduler;
+
+pub use entry::{CronEntry, Job};
+pub use parser::ParseError;
+pub use scheduler::CronScheduler;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Parse(ParseError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "Error reading crontab: {}", e),
+            Error::Parse(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<ParseError> for Error {
+    fn from(e: ParseError) -> Error {
+        Error::Parse(e)
+    }
+}
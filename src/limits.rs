@@ -0,0 +1,233 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Condvar, Mutex};
+
+#[derive(Debug, Default)]
+struct LimitState {
+    max_jobs: Option<usize>,
+    group_max: HashMap<String, usize>,
+    running: usize,
+    group_running: HashMap<String, usize>,
+    // Runs waiting for a slot, in the order they asked for one
+    queue: VecDeque<(u64, Option<String>)>,
+    next_ticket: u64,
+}
+
+impl LimitState {
+    fn global_full(&self) -> bool {
+        self.max_jobs.is_some_and(|max| self.running >= max)
+    }
+
+    fn group_full(&self, group: &Option<String>) -> bool {
+        let group = match group {
+            Some(group) => group,
+            None => return false,
+        };
+        match self.group_max.get(group) {
+            Some(max) => self.group_running.get(group).cloned().unwrap_or(0) >= *max,
+            None => false,
+        }
+    }
+
+    /// A run may start when there is room for it and every run that has
+    /// waited longer is held up by the limit of its own group
+    fn may_start(&self, ticket: u64) -> bool {
+        if self.global_full() {
+            return false;
+        }
+        for (waiting, group) in &self.queue {
+            if *waiting == ticket {
+                return !self.group_full(group);
+            }
+            if !self.group_full(group) {
+                return false;
+            }
+        }
+        false
+    }
+}
+
+/// Limits on how many jobs run at once, globally and per group. Both
+/// schedulers share them, the tokio one waits for slots asynchronously.
+#[derive(Clone, Debug, Default)]
+pub struct Limits {
+    inner: Arc<(Mutex<LimitState>, Condvar)>,
+    #[cfg(feature = "tokio")]
+    changed: Arc<tokio::sync::Notify>,
+}
+
+impl Limits {
+    pub fn new(max_jobs: Option<usize>, group_max: HashMap<String, usize>) -> Limits {
+        let state = LimitState {
+            max_jobs,
+            group_max,
+            ..LimitState::default()
+        };
+        Limits {
+            inner: Arc::new((Mutex::new(state), Condvar::new())),
+            #[cfg(feature = "tokio")]
+            changed: Default::default(),
+        }
+    }
+
+    /// Wait for a free slot, the slot is given back when it is dropped
+    pub fn acquire(&self, group: Option<&str>) -> Slot {
+        let (lock, cvar) = &*self.inner;
+        let ticket = self.enqueue(group);
+        let mut state = lock.lock().unwrap();
+        while !state.may_start(ticket.0) {
+            state = cvar.wait(state).unwrap();
+        }
+        self.start(&mut state, ticket)
+    }
+
+    /// Like `acquire`, but waits without blocking the thread
+    #[cfg(feature = "tokio")]
+    pub async fn acquire_async(&self, group: Option<&str>) -> Slot {
+        let ticket = self.enqueue(group);
+        // Gives up the place in the queue if the run is dropped while waiting
+        let waiting = Waiting(self, ticket.0);
+        loop {
+            // Created before checking, so no wakeup is missed
+            let changed = self.changed.notified();
+            {
+                let mut state = self.inner.0.lock().unwrap();
+                if state.may_start(ticket.0) {
+                    std::mem::forget(waiting);
+                    return self.start(&mut state, ticket);
+                }
+            }
+            changed.await;
+        }
+    }
+
+    /// Take a place in the queue for a slot
+    fn enqueue(&self, group: Option<&str>) -> (u64, Option<String>) {
+        let mut state = self.inner.0.lock().unwrap();
+        let ticket = (state.next_ticket, group.map(String::from));
+        state.next_ticket += 1;
+        state.queue.push_back(ticket.clone());
+        ticket
+    }
+
+    fn start(&self, state: &mut LimitState, (ticket, group): (u64, Option<String>)) -> Slot {
+        state.queue.retain(|(waiting, _)| *waiting != ticket);
+        state.running += 1;
+        if let Some(group) = &group {
+            *state.group_running.entry(group.clone()).or_insert(0) += 1;
+        }
+        // Runs behind this one may be able to start now
+        self.notify();
+        Slot {
+            limits: self.clone(),
+            group,
+        }
+    }
+
+    fn release(&self, group: &Option<String>) {
+        let mut state = self.inner.0.lock().unwrap();
+        state.running -= 1;
+        if let Some(group) = group {
+            if let Some(running) = state.group_running.get_mut(group) {
+                *running -= 1;
+            }
+        }
+        self.notify();
+    }
+
+    fn notify(&self) {
+        self.inner.1.notify_all();
+        #[cfg(feature = "tokio")]
+        self.changed.notify_waiters();
+    }
+}
+
+/// A place in the queue of `acquire_async`, left when it is dropped
+#[cfg(feature = "tokio")]
+struct Waiting<'a>(&'a Limits, u64);
+
+#[cfg(feature = "tokio")]
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        let Waiting(limits, ticket) = self;
+        limits.inner.0.lock().unwrap().queue.retain(|(waiting, _)| waiting != ticket);
+        limits.notify();
+    }
+}
+
+pub struct Slot {
+    limits: Limits,
+    group: Option<String>,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.limits.release(&self.group);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn queue_in_order() {
+        let mut groups = HashMap::new();
+        groups.insert(String::from("backup"), 1);
+        let limits = Limits::new(Some(2), groups);
+
+        let backup = limits.acquire(Some("backup"));
+        let (tx, rx) = channel();
+        let waiting: Vec<_> = vec![Some("backup"), None, None]
+            .into_iter()
+            .enumerate()
+            .map(|(n, group)| {
+                let limits = limits.clone();
+                let tx = tx.clone();
+                let handle = thread::spawn(move || {
+                    let slot = limits.acquire(group);
+                    tx.send(n).unwrap();
+                    slot
+                });
+                // Make sure the runs queue up in order
+                thread::sleep(Duration::from_millis(50));
+                handle
+            })
+            .collect();
+
+        // The second backup run waits for its group, run 1 takes the last slot
+        assert_eq!(rx.recv_timeout(Duration::from_secs(1)), Ok(1));
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+
+        drop(backup);
+        assert_eq!(rx.recv_timeout(Duration::from_secs(1)), Ok(0));
+        let mut slots: Vec<Slot> = waiting.into_iter().take(2).map(|h| h.join().unwrap()).collect();
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+
+        slots.pop();
+        assert_eq!(rx.recv_timeout(Duration::from_secs(1)), Ok(2));
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn acquire_async() {
+        use tokio::time::timeout;
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let limits = Limits::new(Some(1), HashMap::new());
+            let slot = limits.acquire_async(None).await;
+            // A run that stops waiting leaves the queue to the runs behind it
+            let dropped = timeout(Duration::from_millis(50), limits.acquire_async(None)).await;
+            assert!(dropped.is_err());
+            let next = limits.acquire_async(None);
+            drop(slot);
+            timeout(Duration::from_secs(1), next).await.unwrap();
+        });
+    }
+}
@@ -0,0 +1,137 @@
+//! Mailing the output of runs to the address in `MAILTO`, like cron does.
+use std::fs::File;
+use std::io::{self, Write};
+use std::process::Stdio;
+
+use crate::entry::CronEntry;
+use crate::history::Record;
+use crate::runner::shell_command;
+
+/// Sends the output of runs, or nothing unless it was given a command
+#[derive(Clone, Debug, Default)]
+pub struct Mailer {
+    command: Option<String>,
+}
+
+impl Mailer {
+    /// Mail with the shell command `command`, which reads the message and
+    /// its headers from stdin, "sendmail -t -i" for example
+    pub fn new(command: &str) -> Mailer {
+        Mailer {
+            command: Some(command.to_owned()),
+        }
+    }
+
+    /// Mail the output of a run of `entry` to its `MAILTO`, from its
+    /// `MAILFROM` if set. The mail command runs like the job, as its user
+    /// and with its variables. Runs without output and entries with an
+    /// empty `MAILTO` send nothing. Whether a mail was sent.
+    pub fn send(&self, entry: &CronEntry, record: &Record) -> io::Result<bool> {
+        let command = match &self.command {
+            Some(command) => command,
+            None => return Ok(false),
+        };
+        let to = match entry.var("MAILTO") {
+            Some(to) if !to.trim().is_empty() => to,
+            _ => return Ok(false),
+        };
+        let path = match &record.output_path {
+            Some(path) if record.output_size > 0 => path,
+            _ => return Ok(false),
+        };
+
+        let mut headers = format!("To: {}\n", to);
+        if let Some(from) = entry.var("MAILFROM").filter(|from| !from.trim().is_empty()) {
+            headers.push_str(&format!("From: {}\n", from));
+        }
+        // Only the first line, the others would end up as headers
+        let subject = entry.cmd().lines().next().unwrap_or_default();
+        headers.push_str(&format!("Subject: crust: {}\n\n", subject));
+
+        let mut child = shell_command(entry, command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(headers.as_bytes())?;
+            io::copy(&mut File::open(path)?, &mut stdin)?;
+        }
+        let status = child.wait()?;
+        if !status.success() {
+            return Err(io::Error::other(format!(
+                "`{}` failed with {}",
+                command, status
+            )));
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    use crate::parser::parse_crontab;
+    use chrono::Local;
+
+    #[test]
+    fn sends_output() {
+        let dir = TempDir::new("mail");
+        let output = dir.join("output.log");
+        let mail = dir.join("mail.txt");
+        std::fs::write(&output, "hello\n").unwrap();
+
+        let mut record = Record {
+            job: String::from("echo hello"),
+            scheduled: Local::now(),
+            start: Local::now(),
+            end: Local::now(),
+            exit_code: Some(0),
+            signal: None,
+            output_path: Some(output),
+            output_size: 6,
+            timed_out: false,
+            attempt: 1,
+            queue_wait_ms: 0,
+        };
+        let crontab = "MAILTO=ops@example.com\n@daily echo hello\nMAILTO=\n@daily echo hello\n";
+        let entries = parse_crontab(crontab).unwrap();
+        let mailer = Mailer::new(&format!("cat > {}", mail.display()));
+
+        assert!(!Mailer::default().send(&entries[0], &record).unwrap());
+        assert!(!mailer.send(&entries[1], &record).unwrap());
+        assert!(mailer.send(&entries[0], &record).unwrap());
+        assert_eq!(
+            std::fs::read_to_string(&mail).unwrap(),
+            "To: ops@example.com\nSubject: crust: echo hello\n\nhello\n"
+        );
+        let multiline = "MAILTO=ops@example.com\n\
+                         &multiline(on) @daily <<END\necho a\nBcc: x\nEND\n";
+        assert!(mailer.send(&parse_crontab(multiline).unwrap()[0], &record).unwrap());
+        assert_eq!(
+            std::fs::read_to_string(&mail).unwrap(),
+            "To: ops@example.com\nSubject: crust: echo a\n\nhello\n"
+        );
+        record.output_size = 0;
+        assert!(!mailer.send(&entries[0], &record).unwrap());
+
+        // The mail of a user's job is sent as that user, not as root
+        if unsafe { libc::geteuid() } == 0 {
+            use std::os::unix::fs::PermissionsExt;
+
+            let nobody = crate::system::User::by_name("nobody").unwrap();
+            let shared = dir.join("shared");
+            std::fs::create_dir(&shared).unwrap();
+            std::fs::set_permissions(&shared, std::fs::Permissions::from_mode(0o777)).unwrap();
+            let uid = shared.join("uid");
+            let mailer = Mailer::new(&format!("id -u > {}", uid.display()));
+            let mut entry = entries[0].clone();
+            nobody.clone().assign(&mut entry);
+            record.output_size = 6;
+            assert!(mailer.send(&entry, &record).unwrap());
+            let sent_as = std::fs::read_to_string(&uid).unwrap();
+            assert_eq!(sent_as.trim(), nobody.uid.to_string());
+        }
+    }
+}
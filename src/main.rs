@@ -1,242 +1,68 @@
-#[macro_use(quickcheck)]
-use std::fs::File;
-use std::io::prelude::*;
+use std::path::{Path, PathBuf};
 use std::process::Command;
-use std::thread;
-
-use std::sync::mpsc::{channel, Receiver, Sender};
 use std::thread::sleep;
-use std::thread::JoinHandle;
 
 use chrono::prelude::*;
 use chrono::Duration;
-#[macro_use(clap)]
-use clap::{App, Arg};
+use clap::{App, Arg, ArgMatches, SubCommand};
 
 use std::collections::HashMap;
 
-mod parser;
-use parser::parse_crontab;
-
-mod expr;
-use expr::*;
+use crust::config::{self, Config};
+use crust::crontab::Crontab;
+use crust::history::{self, History};
+use crust::limits::Limits;
+use crust::parser::{parse_document_in, parse_duration};
+use crust::scheduler::Configure;
+use crust::system::split_users;
+use crust::CronScheduler;
 
 struct Args {
-    pub crontab_path: String,
+    pub config_path: Option<PathBuf>,
+    pub crontab_paths: Vec<PathBuf>,
+    pub crontab_dirs: Vec<PathBuf>,
+    pub seconds: bool,
+    pub system: bool,
+    pub default_timeout: Option<Duration>,
+    pub max_jobs: Option<usize>,
+    pub group_limits: HashMap<String, usize>,
     pub edit_flag: bool,
+    pub subcommand: Option<Subcommand>,
 }
 
-#[derive(Clone, Debug)]
-pub struct CronEntry {
-    minute: CronExpr,
-    hour: CronExpr,
-    dom: CronExpr,
-    month: CronExpr,
-    dow: CronExpr,
-    startup: bool,
-    cmd: String,
-}
-
-fn is_leap_year(year: i32) -> bool {
-    ((year % 4 == 0) && (year % 100 != 0)) || (year % 400 == 0)
-}
-
-fn month_length(date: &DateTime<Local>) -> u32 {
-    match date.month() {
-        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
-        4 | 6 | 9 | 11 => 30,
-        2 => match is_leap_year(date.year()) {
-            true => 29,
-            false => 28,
-        },
-        _ => unimplemented!(),
-    }
-}
-
-fn month_has_day(date: &DateTime<Local>, day: u32) -> bool {
-    month_length(date) >= day
-}
-
-impl CronEntry {
-    pub fn new_startup_task(cmd: &str) -> CronEntry {
-        CronEntry {
-            minute: CronExpr(CronUnit::Minute, CronInterval::Every),
-            hour: CronExpr(CronUnit::Hour, CronInterval::Every),
-            dom: CronExpr(CronUnit::DayOfMonth, CronInterval::Every),
-            month: CronExpr(CronUnit::Month, CronInterval::Every),
-            dow: CronExpr(CronUnit::DayOfWeek, CronInterval::Every),
-            startup: true,
-            cmd: cmd.to_owned(),
-        }
-    }
-
-    pub fn next_execution(&self, mut now: DateTime<Local>) -> DateTime<Local> {
-        loop {
-            if self.minute.is_multiple() && !self.minute.contains(now.minute()) {
-                let minute = self.minute.next_from(now.minute());
-                if minute < now.minute() {
-                    now = now + Duration::hours(1);
-                }
-                now = now.with_minute(minute).unwrap();
-            }
-
-            if self.hour.is_multiple() && !self.hour.contains(now.hour()) {
-                let hour = self.hour.next_from(now.hour());
-                if hour < now.hour() {
-                    now = now.with_hour(hour).unwrap().with_minute(0).unwrap();
-                    now = now + Duration::days(1);
-                    continue;
-                }
-                now = now.with_hour(hour).unwrap().with_minute(0).unwrap();
-                continue;
-            }
-
-            let weekday = now.weekday().num_days_from_sunday();
-            if self.dow.is_multiple() && !self.dow.contains(weekday) {
-                let delta = (self.dow.next_from(weekday) as i64 - weekday as i64 + 7) % 7;
-                now = now + Duration::days(delta);
-                now = now.with_hour(0).unwrap().with_minute(0).unwrap();
-                continue;
-            }
-
-            let next_month = now.month() % 12 + 1;
-            if self.dom.is_multiple() && !self.dom.contains(now.day()) {
-                let day = self.dom.next_from(now.day());
-                if day < now.day() || month_has_day(&now.with_month(next_month).unwrap(), day) {
-                    let len = month_length(&now);
-                    let days_left_in_month = len - now.day() + 1;
-                    now = now + Duration::days(days_left_in_month as i64);
-                    now = now
-                        .with_day(1)
-                        .unwrap()
-                        .with_hour(0)
-                        .unwrap()
-                        .with_minute(0)
-                        .unwrap();
-                    continue;
-                }
-                now = now
-                    .with_day(day)
-                    .unwrap()
-                    .with_hour(0)
-                    .unwrap()
-                    .with_minute(0)
-                    .unwrap();
-                continue;
-            }
-
-            if self.month.is_multiple() && !self.month.contains(now.month()) {
-                let month = self.month.next_from(now.month());
-                if month < now.month() {
-                    let months_to_add = 12 - now.month() + month;
-                    let next_month = now.month() + months_to_add;
-                    if next_month > 12 {
-                        now.with_year(now.year() + 1)
-                            .unwrap()
-                            .with_month(next_month % 13 + 1)
-                            .unwrap()
-                            .with_day(1)
-                            .unwrap()
-                            .with_hour(0)
-                            .unwrap()
-                            .with_minute(0)
-                            .unwrap();
-                        continue;
-                    }
-                    now.with_month(next_month)
-                        .unwrap()
-                        .with_day(1)
-                        .unwrap()
-                        .with_hour(0)
-                        .unwrap()
-                        .with_minute(0)
-                        .unwrap();
-                    continue;
-                }
-                now.with_month(month)
-                    .unwrap()
-                    .with_day(1)
-                    .unwrap()
-                    .with_hour(0)
-                    .unwrap()
-                    .with_minute(0)
-                    .unwrap();
-                continue;
-            }
-            break;
-        }
-
-        return now.with_second(0).unwrap().with_nanosecond(0).unwrap();
-    }
-}
-
-pub enum Message {
-    Quit,
-}
-pub struct CronJob {
-    entry: CronEntry,
-    tx: Sender<Message>,
-}
-
-impl CronJob {
-    pub fn id(&self) -> &String {
-        &self.entry.cmd
-    }
-    pub fn cancel(&self) {
-        self.tx.send(Message::Quit).unwrap();
-    }
+enum Subcommand {
+    Add(String),
+    Remove(String),
+    Disable(String),
+    History(history::Filter),
 }
 
-pub struct CronScheduler {
-    cron_path: String,
-    jobs: HashMap<String, CronJob>,
-}
-
-impl CronScheduler {
-    pub fn new(cron_path: String) -> CronScheduler {
-        CronScheduler {
-            cron_path,
-            jobs: HashMap::new(),
-        }
-    }
-
-    pub fn read_crontab(&mut self) -> std::io::Result<()> {
-        let mut crontab_file = File::open(&self.cron_path)?;
-        let mut crontab_string = String::new();
-        crontab_file.read_to_string(&mut crontab_string)?;
-
-        let crontab = parse_crontab(&crontab_string);
-        for entry in crontab {
-            self.start_job(entry);
-            sleep(Duration::milliseconds(100).to_std().unwrap());
-        }
-        return Ok(());
-    }
-
-    pub fn start_job(&mut self, entry: CronEntry) {
-        let (tx, rx) = channel();
-        spawn_job(&entry, rx);
-        let cronjob = CronJob { entry, tx };
-        self.jobs.insert(cronjob.id().clone(), cronjob);
-    }
-
-    pub fn clear(&mut self) {
-        for job in self.jobs.values() {
-            job.cancel();
-        }
-        self.jobs.clear();
-    }
+/// The settings of the config file with the flags given on top
+struct Settings {
+    /// The crontab that is edited, the first one that is read
+    crontab_path: String,
+    crontab_paths: Vec<PathBuf>,
+    crontab_dirs: Vec<PathBuf>,
+    spool_dirs: Vec<PathBuf>,
+    seconds: bool,
+    system: bool,
+    default_timeout: Option<Duration>,
+    limits: Limits,
+    history: History,
+    history_retention: Option<Duration>,
+    config: Config,
 }
 
 fn main() {
     let args = gen_args();
+    let settings = load_settings(&args);
 
     if args.edit_flag {
         if std::env::var("EDITOR").is_err() {
             println!("Error reading $EDITOR, make sure it is set correctly and try again");
             std::process::exit(1);
         }
-        let cmd = vec!["$EDITOR", &args.crontab_path].join(" ");
+        let cmd = ["$EDITOR", &settings.crontab_path].join(" ");
         Command::new("/bin/sh")
             .arg("-c")
             .arg(&cmd)
@@ -245,15 +71,78 @@ fn main() {
         std::process::exit(1);
     }
 
-    start_cronjobs(args.crontab_path);
+    match args.subcommand {
+        Some(Subcommand::History(filter)) => {
+            show_history(&settings.history, &filter);
+            return;
+        }
+        Some(subcommand) => {
+            modify_crontab(&settings, subcommand);
+            return;
+        }
+        None => {}
+    }
+
+    let _scheduler = start_cronjobs(&settings);
 
-    // Without sleeping the program will grab as much resources as it can
-    // leading to 100% cpu usage..
+    let mut last_prune: Option<DateTime<Local>> = None;
     loop {
+        if let Some(retention) = settings.history_retention {
+            if last_prune.is_none_or(|last| Local::now() - last >= Duration::hours(1)) {
+                last_prune = Some(Local::now());
+                if let Err(e) = settings.history.prune(Local::now() - retention) {
+                    println!("Error pruning history: {}", e);
+                }
+            }
+        }
+        // Without sleeping the program will grab as much resources as it can
+        // leading to 100% cpu usage..
         sleep(Duration::seconds(10).to_std().unwrap());
     }
 }
 
+/// Read the config file and apply the flags on top of it
+fn load_settings(args: &Args) -> Settings {
+    let config = match &args.config_path {
+        Some(path) => Config::load(path),
+        None if Config::default_path().exists() => Config::load(&Config::default_path()),
+        None => Ok(Config::default()),
+    };
+    let mut config = config.unwrap_or_else(|e| {
+        println!("{}", e);
+        std::process::exit(1);
+    });
+    config.system |= args.system;
+
+    let mut crontab_paths = args.crontab_paths.clone();
+    if crontab_paths.is_empty() {
+        crontab_paths = config.crontab_files();
+    }
+    if crontab_paths.is_empty() {
+        crontab_paths.push(config::config_home().join("crontab"));
+    }
+    let crontab_dirs = if args.crontab_dirs.is_empty() {
+        config.crontab_dirs()
+    } else {
+        args.crontab_dirs.clone()
+    };
+    let mut group_limits = config.group_limits.clone();
+    group_limits.extend(args.group_limits.clone());
+    Settings {
+        crontab_path: crontab_paths[0].to_string_lossy().into_owned(),
+        crontab_paths,
+        crontab_dirs,
+        spool_dirs: config.spool_dirs(),
+        seconds: args.seconds || config.seconds,
+        system: config.system,
+        default_timeout: args.default_timeout.or(config.timeout),
+        limits: Limits::new(args.max_jobs.or(config.max_jobs), group_limits),
+        history: History::new(config.log_dir()),
+        history_retention: config.history_retention,
+        config,
+    }
+}
+
 fn gen_args() -> Args {
     let matches = App::new(clap::crate_name!())
         .version(clap::crate_version!())
@@ -263,10 +152,60 @@ fn gen_args() -> Args {
             Arg::with_name("crontab")
                 .short("c")
                 .long("crontab")
-                .help("Use crontab file at PATH")
+                .help("Use crontab file at PATH instead of $XDG_CONFIG_HOME/crontab or the crontabs of the config, the first one given is edited")
                 .value_name("PATH")
                 .takes_value(true)
-                .default_value("$XDG_CONFIG_HOME/crontab"),
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("crontab-dir")
+                .long("crontab-dir")
+                .help("Also read the crontabs in DIR instead of $XDG_CONFIG_HOME/crust/cron.d or the crontab_dirs of the config")
+                .value_name("DIR")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .help("Read settings from PATH instead of $XDG_CONFIG_HOME/crust/config.toml")
+                .value_name("PATH")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("seconds")
+                .long("seconds")
+                .help("Read entries with a leading seconds field, like `!seconds(on)`"),
+        )
+        .arg(
+            Arg::with_name("system")
+                .long("system")
+                .help("Run as the system cron: read /etc/crontab, /etc/cron.d and the crontabs of users, and run jobs as their users"),
+        )
+        .arg(
+            Arg::with_name("timeout")
+                .long("timeout")
+                .help("Terminate runs of jobs without a timeout option after DURATION, e.g. \"2h\"")
+                .value_name("DURATION")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-jobs")
+                .long("max-jobs")
+                .help("Run at most N jobs at once, further runs wait in line")
+                .value_name("N")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("group-limit")
+                .long("group-limit")
+                .help("Run at most N jobs of the group NAME at once")
+                .value_name("NAME=N")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
         )
         .arg(
             Arg::with_name("edit")
@@ -274,55 +213,250 @@ fn gen_args() -> Args {
                 .long("edit")
                 .help("Open the crontab in your editor"),
         )
+        .subcommand(
+            SubCommand::with_name("add")
+                .about("Append an entry to the crontab")
+                .arg(
+                    Arg::with_name("entry")
+                        .help("The crontab line to add, e.g. \"@daily backup.sh\"")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("remove")
+                .about("Remove all entries running COMMAND from the crontab")
+                .arg(Arg::with_name("command").value_name("COMMAND").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("disable")
+                .about("Comment out all entries running COMMAND in the crontab")
+                .arg(Arg::with_name("command").value_name("COMMAND").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("history")
+                .about("Show past runs, optionally only of jobs whose command contains JOB")
+                .arg(Arg::with_name("job").value_name("JOB"))
+                .arg(
+                    Arg::with_name("since")
+                        .long("since")
+                        .value_name("TIME")
+                        .help("Only show runs started after TIME, e.g. \"2h\" or \"2019-08-01\""),
+                )
+                .arg(
+                    Arg::with_name("until")
+                        .long("until")
+                        .value_name("TIME")
+                        .help("Only show runs started before TIME"),
+                )
+                .arg(
+                    Arg::with_name("failed")
+                        .long("failed")
+                        .help("Only show failed runs"),
+                ),
+        )
         .get_matches();
 
-    let config_path = matches.value_of("crontab").unwrap();
-    let home = std::env::var("HOME").unwrap_or(String::from("/"));
-    let xdg_config_path =
-        std::env::var("XDG_CONFIG_HOME").unwrap_or(vec![home, String::from("/.config")].join(""));
-    let crontab_path = config_path.replace("$XDG_CONFIG_HOME", &xdg_config_path);
+    let subcommand = match matches.subcommand() {
+        ("add", Some(m)) => Some(Subcommand::Add(m.value_of("entry").unwrap().to_owned())),
+        ("remove", Some(m)) => Some(Subcommand::Remove(
+            m.value_of("command").unwrap().to_owned(),
+        )),
+        ("disable", Some(m)) => Some(Subcommand::Disable(
+            m.value_of("command").unwrap().to_owned(),
+        )),
+        ("history", Some(m)) => Some(Subcommand::History(history::Filter {
+            job: m.value_of("job").map(String::from),
+            since: m.value_of("since").map(parse_time),
+            until: m.value_of("until").map(parse_time),
+            failed_only: m.is_present("failed"),
+        })),
+        _ => None,
+    };
+
+    let default_timeout = matches.value_of("timeout").map(|timeout| {
+        match parse_duration(timeout) {
+            Some(timeout) if timeout > Duration::zero() => timeout,
+            _ => {
+                println!("Error: Invalid timeout `{}`", timeout);
+                std::process::exit(1);
+            }
+        }
+    });
+
+    let max_jobs = matches.value_of("max-jobs").map(|n| match n.parse::<usize>() {
+        Ok(n) if n > 0 => n,
+        _ => {
+            println!("Error: Invalid number of jobs `{}`", n);
+            std::process::exit(1);
+        }
+    });
+
+    let mut group_limits = HashMap::new();
+    for limit in matches.values_of("group-limit").into_iter().flatten() {
+        let mut parts = limit.splitn(2, '=');
+        match (parts.next(), parts.next().map(str::parse::<usize>)) {
+            (Some(name), Some(Ok(n))) if !name.is_empty() && n > 0 => {
+                group_limits.insert(name.to_owned(), n);
+            }
+            _ => {
+                println!("Error: Invalid group limit `{}`, expected NAME=N", limit);
+                std::process::exit(1);
+            }
+        }
+    }
 
     Args {
-        crontab_path: String::from(crontab_path),
+        config_path: matches.value_of("config").map(config::expand),
+        crontab_paths: values(&matches, "crontab"),
+        crontab_dirs: values(&matches, "crontab-dir"),
+        seconds: matches.is_present("seconds"),
+        system: matches.is_present("system"),
+        default_timeout,
+        max_jobs,
+        group_limits,
         edit_flag: matches.is_present("edit"),
+        subcommand,
     }
 }
 
-fn start_cronjobs(cron_path: String) {
-    let mut scheduler = CronScheduler::new(cron_path.clone());
-    match scheduler.read_crontab() {
-        Err(_) => panic!("Failed to read crontab!"),
-        _ => {}
-    };
+/// The paths given to a flag that can be repeated
+fn values(matches: &ArgMatches, name: &str) -> Vec<PathBuf> {
+    matches
+        .values_of(name)
+        .map(|values| values.map(config::expand).collect())
+        .unwrap_or_default()
 }
 
-fn spawn_job(entry: &CronEntry, rx: Receiver<Message>) -> JoinHandle<()> {
-    let entry = (*entry).clone();
-    thread::spawn(move || loop {
-        if entry.startup {
-            Command::new("/bin/sh")
-                .arg("-c")
-                .arg(&entry.cmd)
-                .spawn()
-                .expect("failed to execute process");
-            break;
+/// Parse a point in time given either as a date, a date and time, or a
+/// duration counting back from now
+fn parse_time(time: &str) -> DateTime<Local> {
+    if let Some(duration) = parse_duration(time) {
+        return Local::now() - duration;
+    }
+    if let Ok(time) = DateTime::parse_from_rfc3339(time) {
+        return time.with_timezone(&Local);
+    }
+    if let Ok(time) = NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M") {
+        if let Some(time) = Local.from_local_datetime(&time).earliest() {
+            return time;
         }
-        let future = entry.next_execution(Local::now() + Duration::minutes(1));
-        println!("Scheduling: `{}` for {}", entry.cmd, future);
-        let t = future - Local::now();
-        sleep(t.to_std().unwrap());
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(time, "%Y-%m-%d") {
+        if let Some(time) = Local.from_local_datetime(&date.and_hms(0, 0, 0)).earliest() {
+            return time;
+        }
+    }
+    println!("Error: Could not parse time `{}`", time);
+    std::process::exit(1);
+}
+
+fn start_cronjobs(settings: &Settings) -> CronScheduler {
+    let mut scheduler = CronScheduler::new(settings.crontab_path.clone(), settings.history.clone());
+    for path in &settings.crontab_paths[1..] {
+        scheduler.add_crontab(path);
+    }
+    for dir in &settings.crontab_dirs {
+        scheduler.add_crontab_dir(dir);
+    }
+    for dir in &settings.spool_dirs {
+        scheduler.add_spool_dir(dir);
+    }
+    scheduler.set_system(settings.system);
+    scheduler.set_seconds(settings.seconds);
+    scheduler.set_default_timeout(settings.default_timeout);
+    scheduler.set_limits(settings.limits.clone());
+    scheduler.set_env(settings.config.env());
+    scheduler.set_mailer(settings.config.mailer());
+    if let Err(e) = scheduler.read_crontab() {
+        println!("{}", e);
+        std::process::exit(1);
+    }
+    scheduler
+}
+
+fn modify_crontab(settings: &Settings, subcommand: Subcommand) {
+    let path = &settings.crontab_path;
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => {
+            println!("Error reading crontab {}: {}", path, e);
+            std::process::exit(1);
+        }
+    };
+    let mut crontab = Crontab::parse_with_seconds(&text, settings.seconds);
 
-        // Check if this is a stale thread
-        if let Ok(msg) = rx.try_recv() {
-            match msg {
-                Message::Quit => return,
+    match subcommand {
+        Subcommand::Add(entry) => {
+            if !crontab.push_entry(entry.trim()) {
+                println!("Error: `{}` is not a crontab entry", entry);
+                std::process::exit(1);
+            }
+            let parsed = parse_document_in(&crontab, Path::new(path));
+            let parsed = match settings.system {
+                true => parsed.and_then(split_users),
+                false => parsed,
+            };
+            if let Err(e) = parsed {
+                println!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        Subcommand::Remove(cmd) => {
+            if crontab.remove_entries(|line| line.command() == Some(&cmd)) == 0 {
+                println!("Error: No entry runs `{}`", cmd);
+                std::process::exit(1);
+            }
+        }
+        Subcommand::Disable(cmd) => {
+            if crontab.disable_entries(|line| line.command() == Some(&cmd)) == 0 {
+                println!("Error: No enabled entry runs `{}`", cmd);
+                std::process::exit(1);
             }
         }
+        Subcommand::History(_) => unreachable!(),
+    }
 
-        Command::new("/bin/sh")
-            .arg("-c")
-            .arg(&entry.cmd)
-            .spawn()
-            .expect("failed to execute process");
-    })
+    if let Err(e) = std::fs::write(path, crontab.to_string()) {
+        println!("Error writing crontab {}: {}", path, e);
+        std::process::exit(1);
+    }
+}
+
+fn show_history(history: &History, filter: &history::Filter) {
+    let records = match history.query(filter) {
+        Ok(records) => records,
+        Err(e) => {
+            println!("Error reading history: {}", e);
+            std::process::exit(1);
+        }
+    };
+    for record in records {
+        let status = match (record.exit_code, record.signal) {
+            _ if record.timed_out => String::from("timed out"),
+            (Some(code), _) => format!("exit {}", code),
+            (None, Some(signal)) => format!("signal {}", signal),
+            (None, None) => String::from("not started"),
+        };
+        let status = match record.attempt {
+            1 => status,
+            attempt => format!("{}, try {}", status, attempt),
+        };
+        let status = match record.queue_wait_ms / 1000 {
+            0 => status,
+            wait => format!("{}, queued {}s", status, wait),
+        };
+        let output = match &record.output_path {
+            Some(path) => format!("{} bytes in {}", record.output_size, path.display()),
+            None => String::from("no output"),
+        };
+        println!(
+            "{}  {:>6}s  {:<20}  {}  ({})",
+            record.start.format("%Y-%m-%d %H:%M:%S"),
+            (record.end - record.start).num_seconds(),
+            status,
+            record.job,
+            output
+        );
+    }
 }
@@ -0,0 +1,216 @@
+use chrono::Duration;
+use std::fmt;
+
+use crate::parser::format_duration;
+
+/// The most missed runs `catchup(all)` will run, unless given as `all:N`
+pub const DEFAULT_CATCHUP_LIMIT: u32 = 10;
+
+/// The longest duration a crontab can give, a thousand years, so that any
+/// of them can still be added to a time
+pub(crate) const MAX_DURATION_SECONDS: i64 = 1000 * 366 * 86400;
+
+/// What to do with runs that were missed while crust was not running
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CatchUp {
+    Skip,
+    Once,
+    /// Run every missed occurrence, but at most the given number of runs
+    All(u32),
+}
+
+/// What to do when a job is due while a previous run is still going
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Concurrency {
+    Allow,
+    Skip,
+    /// Start once the previous run has finished
+    Queue,
+    /// Kill the previous run and start a new one
+    Replace,
+}
+
+/// How failed runs are retried, the delay before retry n is
+/// `delay * backoff^(n - 1)` plus a random jitter of up to `jitter`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Retry {
+    /// The most runs per scheduled time, including the first one
+    pub attempts: u32,
+    pub delay: Duration,
+    pub backoff: f64,
+    pub jitter: Duration,
+    /// Only retry runs exiting with one of these codes, any failure if empty
+    pub exit_codes: Vec<i32>,
+}
+
+impl Retry {
+    /// The delay before retry `retry`, at most `MAX_DURATION_SECONDS`
+    pub fn delay(&self, retry: u32) -> Duration {
+        let millis = self.millis(retry).min(MAX_DURATION_SECONDS as f64 * 1000.0);
+        Duration::milliseconds(millis as i64)
+    }
+
+    /// Whether the delay before the last retry, with its jitter, stays within
+    /// `MAX_DURATION_SECONDS`
+    pub(crate) fn is_bounded(&self) -> bool {
+        if self.attempts < 2 {
+            return true;
+        }
+        let longest = self.millis(self.attempts - 1) + self.jitter.num_milliseconds() as f64;
+        longest <= MAX_DURATION_SECONDS as f64 * 1000.0
+    }
+
+    /// The delay before retry `retry` in milliseconds, without a cap. The
+    /// factor is capped though, so that a zero delay stays zero.
+    fn millis(&self, retry: u32) -> f64 {
+        let exponent = retry.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let factor = self.backoff.powi(exponent).min(MAX_DURATION_SECONDS as f64 * 1000.0);
+        self.delay.num_milliseconds() as f64 * factor
+    }
+}
+
+impl Default for Retry {
+    fn default() -> Retry {
+        Retry {
+            attempts: 1,
+            delay: Duration::minutes(1),
+            backoff: 2.0,
+            jitter: Duration::zero(),
+            exit_codes: Vec::new(),
+        }
+    }
+}
+
+/// Per job settings, given as `&name(value),...` in front of an entry or as
+/// `!name(value),...` lines that set the defaults for the following entries.
+#[derive(Clone, Debug, PartialEq)]
+pub struct JobOptions {
+    pub catchup: CatchUp,
+    /// Upper bound of the random delay before a catch-up run
+    pub random_delay: Option<Duration>,
+    /// Catch-up runs only start between these hours, end exclusive
+    pub start_hours: Option<(u32, u32)>,
+    pub concurrency: Concurrency,
+    /// How long a run may take before it is terminated, a zero duration
+    /// means no limit even if there is a global default
+    pub timeout: Option<Duration>,
+    pub retry: Retry,
+    /// Runs of jobs in the same group share the group's concurrency limit
+    pub group: Option<String>,
+    /// Upper bound of a delay for every run that is the same each time for
+    /// a given job and host
+    pub start_delay: Option<Duration>,
+    /// @reboot jobs wait until this shell command succeeds
+    pub after: Option<String>,
+}
+
+impl Default for JobOptions {
+    fn default() -> JobOptions {
+        JobOptions {
+            catchup: CatchUp::Skip,
+            random_delay: None,
+            start_hours: None,
+            concurrency: Concurrency::Allow,
+            timeout: None,
+            retry: Retry::default(),
+            group: None,
+            start_delay: None,
+            after: None,
+        }
+    }
+}
+
+/// The options that differ from the defaults, as written after `&` in front
+/// of an entry. Empty if all options are the defaults.
+impl fmt::Display for JobOptions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let defaults = JobOptions::default();
+        let mut options = Vec::new();
+        match self.catchup {
+            CatchUp::Skip => {}
+            CatchUp::Once => options.push("catchup(once)".to_owned()),
+            CatchUp::All(limit) if limit == DEFAULT_CATCHUP_LIMIT => {
+                options.push("catchup(all)".to_owned())
+            }
+            CatchUp::All(limit) => options.push(format!("catchup(all:{})", limit)),
+        }
+        if let Some(delay) = self.random_delay {
+            options.push(format!("random_delay({})", format_duration(delay)));
+        }
+        if let Some((start, end)) = self.start_hours {
+            options.push(format!("start_hours({}-{})", start, end));
+        }
+        let concurrency = match self.concurrency {
+            Concurrency::Allow => None,
+            Concurrency::Skip => Some("skip"),
+            Concurrency::Queue => Some("queue"),
+            Concurrency::Replace => Some("replace"),
+        };
+        if let Some(concurrency) = concurrency {
+            options.push(format!("concurrency({})", concurrency));
+        }
+        match self.timeout {
+            Some(timeout) if timeout <= Duration::zero() => options.push("timeout(none)".to_owned()),
+            Some(timeout) => options.push(format!("timeout({})", format_duration(timeout))),
+            None => {}
+        }
+        if self.retry.attempts != defaults.retry.attempts {
+            options.push(format!("attempts({})", self.retry.attempts));
+        }
+        if self.retry.delay != defaults.retry.delay {
+            options.push(format!("retry_delay({})", format_duration(self.retry.delay)));
+        }
+        if self.retry.backoff != defaults.retry.backoff {
+            options.push(format!("retry_backoff({})", self.retry.backoff));
+        }
+        if self.retry.jitter != defaults.retry.jitter {
+            options.push(format!("retry_jitter({})", format_duration(self.retry.jitter)));
+        }
+        if !self.retry.exit_codes.is_empty() {
+            let codes: Vec<String> = self.retry.exit_codes.iter().map(i32::to_string).collect();
+            options.push(format!("retry_on({})", codes.join(",")));
+        }
+        if let Some(group) = &self.group {
+            options.push(format!("group({})", group));
+        }
+        if let Some(delay) = self.start_delay {
+            options.push(format!("start_delay({})", format_duration(delay)));
+        }
+        if let Some(after) = &self.after {
+            options.push(format!("after({})", after));
+        }
+        f.write_str(&options.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_delay() {
+        let retry = Retry {
+            delay: Duration::seconds(30),
+            backoff: 1.5,
+            ..Retry::default()
+        };
+        assert_eq!(retry.delay(1), Duration::seconds(30));
+        assert_eq!(retry.delay(2), Duration::seconds(45));
+        assert_eq!(retry.delay(3), Duration::milliseconds(67500));
+        assert!(retry.is_bounded());
+
+        let retry = Retry {
+            attempts: 100,
+            backoff: 100.0,
+            ..Retry::default()
+        };
+        assert_eq!(retry.delay(99), Duration::seconds(MAX_DURATION_SECONDS));
+        assert!(!retry.is_bounded());
+        let retry = Retry {
+            delay: Duration::zero(),
+            ..retry
+        };
+        assert_eq!(retry.delay(99), Duration::zero());
+        assert!(retry.is_bounded());
+    }
+}
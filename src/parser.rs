@@ -1,84 +1,393 @@
-use crate::*;
+use crate::crontab::{heredoc_word, split_options, Crontab, LineKind};
+use crate::entry::CronEntry;
+use crate::expr::*;
+use crate::options::{CatchUp, Concurrency, JobOptions, DEFAULT_CATCHUP_LIMIT, MAX_DURATION_SECONDS};
+use crate::schedule::Schedule;
+use crate::sources::include_paths;
+use crate::system::untrusted;
+use chrono::prelude::*;
+use chrono::Duration;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
-pub fn parse_crontab(crontab: &String) -> Vec<CronEntry> {
+/// Why a crontab could not be read
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseError {
+    /// The crontab file, None if the crontab was not read from a file
+    pub path: Option<PathBuf>,
+    /// The line of the crontab, counted from 1
+    pub line: usize,
+    pub message: String,
+}
+
+impl ParseError {
+    pub(crate) fn new(line: usize, message: String) -> ParseError {
+        ParseError {
+            path: None,
+            line: line + 1,
+            message,
+        }
+    }
+
+    /// The same error, in the crontab at `path` unless it was found in a
+    /// crontab that one includes
+    pub(crate) fn in_file(mut self, path: &Path) -> ParseError {
+        self.path.get_or_insert_with(|| path.to_owned());
+        self
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.path {
+            Some(path) => write!(f, "Parse error in {}: {}", path.display(), self.message),
+            None => write!(f, "Parse error: {}", self.message),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+pub fn parse_crontab(crontab: &str) -> Result<Vec<CronEntry>, ParseError> {
+    parse_document(&Crontab::parse(crontab))
+}
+
+/// Parse a crontab that was not read from a file, it includes crontabs
+/// relative to the working directory
+pub fn parse_document(document: &Crontab) -> Result<Vec<CronEntry>, ParseError> {
+    let defaults = JobOptions::default();
+    parse_lines(document, Path::new(""), &mut Vec::new(), None, Vec::new(), defaults)
+}
+
+/// Parse the crontab read from the file at `path`, it includes crontabs
+/// relative to its directory
+pub fn parse_document_in(document: &Crontab, path: &Path) -> Result<Vec<CronEntry>, ParseError> {
+    parse_file(document, path, None)
+}
+
+/// Like `parse_document_in`, but crontabs that do not belong to the user
+/// `owner`, or that others can write to, are not included but reported
+pub(crate) fn parse_document_owned(
+    document: &Crontab,
+    path: &Path,
+    owner: libc::uid_t,
+) -> Result<Vec<CronEntry>, ParseError> {
+    parse_file(document, path, Some(owner))
+}
+
+fn parse_file(
+    document: &Crontab,
+    path: &Path,
+    owner: Option<libc::uid_t>,
+) -> Result<Vec<CronEntry>, ParseError> {
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    let mut including: Vec<PathBuf> = fs::canonicalize(path).into_iter().collect();
+    parse_lines(document, dir, &mut including, owner, Vec::new(), JobOptions::default())
+        .map_err(|e| e.in_file(path))
+}
+
+/// Parse the lines of `document`, whose `@include`s are relative to `dir`.
+/// `including` are the canonical paths of the crontabs being read, to find
+/// cycles. Only crontabs of `owner` are included if it is given. The crontab
+/// starts with the variables and defaults of the line that includes it,
+/// what it sets itself stays within the crontab.
+fn parse_lines(
+    document: &Crontab,
+    dir: &Path,
+    including: &mut Vec<PathBuf>,
+    owner: Option<libc::uid_t>,
+    mut env: Vec<(String, String)>,
+    mut defaults: JobOptions,
+) -> Result<Vec<CronEntry>, ParseError> {
     let mut entries = Vec::new();
-    for (line, entry) in crontab.lines().enumerate() {
-        let mut entry = entry.trim().to_owned();
-        // Remove all comments
-        if entry.starts_with("#") || entry.is_empty() {
-            //println!("Comment: {}", entry);
-            continue;
+    for (line, node) in document.numbered_lines() {
+        let fields = match node.kind() {
+            // Skip comments and empty lines
+            LineKind::Blank | LineKind::Comment => continue,
+            LineKind::Env { name, value } => {
+                env.push((
+                    name.of(node.text()).to_owned(),
+                    value.of(node.text()).to_owned(),
+                ));
+                continue;
+            }
+            LineKind::Defaults(span) => {
+                defaults = parse_options(defaults, span.of(node.text()), line)?;
+                continue;
+            }
+            LineKind::Include(span) => {
+                let pattern = span.of(node.text());
+                let paths = include_paths(dir, pattern).map_err(|e| {
+                    ParseError::new(
+                        line,
+                        format!("Could not include {}, line {}: {}", pattern, line + 1, e),
+                    )
+                })?;
+                for path in paths {
+                    let included = Include {
+                        path: &path,
+                        line,
+                        seconds: node.has_seconds(),
+                        owner,
+                    };
+                    entries.append(&mut included.parse(including, env.clone(), defaults.clone())?);
+                }
+                continue;
+            }
+            LineKind::Entry(entry) => &entry.fields,
+        };
+        let options = match node.options() {
+            Some(options) => parse_options(defaults.clone(), options, line)?,
+            None => defaults.clone(),
+        };
+
+        let cmd = node.command().unwrap_or_default();
+        let cmd = match node.block_end() {
+            Some(word) => block_command(cmd, word, line)?,
+            None => cmd,
+        };
+        if cmd.is_empty() {
+            return Err(ParseError::new(
+                line,
+                format!(
+                    "Missing command after schedule, line {}: {}",
+                    line + 1,
+                    node.text().trim()
+                ),
+            ));
         }
 
+        let schedule = node.schedule().unwrap_or_default();
+        if options.after.is_some() && !schedule.starts_with("@reboot") {
+            return Err(ParseError::new(
+                line,
+                format!(
+                    "The after option only applies to @reboot entries, line {}: {}",
+                    line + 1,
+                    node.text().trim()
+                ),
+            ));
+        }
         // Convert the nonstandard defenitions to normal form
-        if entry.starts_with("@") {
+        let schedule = if schedule.starts_with('@') {
             // Reboot is a special case as there is no normal form equivalent
-            if entry.split_whitespace().nth(0).unwrap() == "@reboot" {
-                let cmd: Vec<&str> = entry.split_whitespace().skip(1).collect();
-                if cmd.len() == 0 {
-                    println!(
-                        "Parse error: Missing command after predicate, line {}: {}",
-                        line, entry
-                    );
-                    std::process::exit(1);
-                }
-                entries.push(CronEntry::new_startup_task(&cmd.join(" ")));
+            if let Some(delay) = schedule.strip_prefix("@reboot") {
+                // `@reboot+5m` delays the run
+                let delay = match delay.strip_prefix('+').map(parse_duration) {
+                    None if delay.is_empty() => Duration::zero(),
+                    Some(Some(delay)) => delay,
+                    _ => {
+                        return Err(ParseError::new(
+                            line,
+                            format!(
+                                "Invalid delay after @reboot, line {}: {}",
+                                line + 1,
+                                schedule
+                            ),
+                        ));
+                    }
+                };
+                let mut entry = CronEntry::new_startup_task(cmd);
+                entry.startup = Some(delay);
+                entry.env = env.clone();
+                entry.options = options;
+                entry.source.line = line + 1;
+                entries.push(entry);
+                continue;
+            }
+            if let Some(expr) = schedule.strip_prefix("@every") {
+                let every = parse_every(expr, line)?;
+                let mut entry = CronEntry::new_interval_task(cmd, every);
+                entry.env = env.clone();
+                entry.options = options;
+                entry.source.line = line + 1;
+                entries.push(entry);
                 continue;
             }
-            entry = parse_special_expression(&entry, line);
+            parse_special_expression(schedule, line)?
+        } else if fields.len() < 5 {
+            return Err(ParseError::new(
+                line,
+                format!(
+                    "Expected {} time fields, line {}: {}",
+                    if node.has_seconds() { 6 } else { 5 },
+                    line + 1,
+                    node.text().trim()
+                ),
+            ));
+        } else {
+            schedule
+        };
+
+        let mut units = vec![
+            CronUnit::Minute,
+            CronUnit::Hour,
+            CronUnit::DayOfMonth,
+            CronUnit::Month,
+            CronUnit::DayOfWeek,
+        ];
+        let mut intervals = Vec::with_capacity(7);
+        if node.has_seconds() {
+            units.insert(0, CronUnit::Second);
+        } else {
+            intervals.push(CronInterval::Multiple(Bits(1)));
+        }
+        if node.has_year() {
+            units.push(CronUnit::Year);
+        }
+        for (expr, cron_expr_kind) in schedule.split_whitespace().zip(units) {
+            let seed = job_hash(cmd, &format!("{:?}", cron_expr_kind));
+            if let Some(interval) = parse_cron_time(&cron_expr_kind, expr, seed, line)? {
+                intervals.push(interval)
+            } else {
+                return Err(ParseError::new(
+                    line,
+                    format!("Could not match any expression at line {}", line + 1),
+                ));
+            }
+        }
+        if !node.has_year() {
+            intervals.push(CronInterval::Every);
         }
 
-        //println!("{}", entry);
+        let schedule = Schedule {
+            second: CronExpr(CronUnit::Second, intervals[0].clone()),
+            minute: CronExpr(CronUnit::Minute, intervals[1].clone()),
+            hour: CronExpr(CronUnit::Hour, intervals[2].clone()),
+            dom: CronExpr(CronUnit::DayOfMonth, intervals[3].clone()),
+            month: CronExpr(CronUnit::Month, intervals[4].clone()),
+            dow: CronExpr(CronUnit::DayOfWeek, intervals[5].clone()),
+            year: CronExpr(CronUnit::Year, intervals[6].clone()),
+        };
+        if !has_possible_day(&schedule) {
+            return Err(ParseError::new(
+                line,
+                format!(
+                    "Day of month never occurs in the given months, line {}: {}",
+                    line + 1,
+                    node.text().trim()
+                ),
+            ));
+        }
+        let mut entry = CronEntry::new(cmd, schedule);
+        entry.env = env.clone();
+        entry.options = options;
+        entry.source.line = line + 1;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
 
-        let mut intervals = Vec::with_capacity(5);
-        for (index, expr) in entry.split_whitespace().take(5).enumerate() {
-            use CronUnit::*;
-            let cron_expr_kind = match index {
-                0 => Minute,
-                1 => Hour,
-                2 => DayOfMonth,
-                3 => Month,
-                4 => DayOfWeek,
-                _ => unreachable!(),
-            };
+/// The command of an entry with a block: the lines of the block if the
+/// command is just `<<WORD`, otherwise all of it for the shell to read as a
+/// here-document
+fn block_command<'a>(cmd: &'a str, word: &str, line: usize) -> Result<&'a str, ParseError> {
+    match cmd.rfind('\n') {
+        Some(last) if cmd[last + 1..].trim() == word => Ok(block_body(cmd).unwrap_or(cmd)),
+        _ => Err(ParseError::new(
+            line,
+            format!("Missing `{}` at the end of the block, line {}", word, line + 1),
+        )),
+    }
+}
 
-            if let Some(interval) = parse_cron_time(&cron_expr_kind, expr, line) {
-                intervals.push(interval)
-            } else {
-                println!(
-                    "Parse error: Could not match any expression at line {}",
-                    line + 1
-                );
-                std::process::exit(1);
-            }
-        }
-        let str_vec: Vec<&str> = entry.split_whitespace().skip(5).collect();
-
-        let e = CronEntry {
-            minute: CronExpr(CronUnit::Minute, intervals[0].clone()),
-            hour: CronExpr(CronUnit::Hour, intervals[1].clone()),
-            dom: CronExpr(CronUnit::DayOfMonth, intervals[2].clone()),
-            month: CronExpr(CronUnit::Month, intervals[3].clone()),
-            dow: CronExpr(CronUnit::DayOfWeek, intervals[4].clone()),
-            startup: false,
-            cmd: str_vec.join(" "),
+/// The lines between the first and the last line of a command that is just
+/// a `<<WORD` block
+pub(crate) fn block_body(cmd: &str) -> Option<&str> {
+    let (first, rest) = cmd.split_once('\n')?;
+    let word = heredoc_word(first).filter(|_| first.starts_with("<<"))?;
+    let (body, last) = rest.rsplit_once('\n').unwrap_or(("", rest));
+    Some(body).filter(|_| last.trim() == word)
+}
+
+/// A crontab included on `line` of the crontab being read
+struct Include<'a> {
+    path: &'a Path,
+    line: usize,
+    /// Whether its entries have a seconds field unless they say otherwise
+    seconds: bool,
+    /// The user the crontab has to belong to, if any
+    owner: Option<libc::uid_t>,
+}
+
+impl Include<'_> {
+    /// The entries of the included crontab, errors in it name its path
+    fn parse(
+        &self,
+        including: &mut Vec<PathBuf>,
+        env: Vec<(String, String)>,
+        defaults: JobOptions,
+    ) -> Result<Vec<CronEntry>, ParseError> {
+        let read_error = |e: io::Error| {
+            ParseError::new(
+                self.line,
+                format!(
+                    "Could not include {}, line {}: {}",
+                    self.path.display(),
+                    self.line + 1,
+                    e
+                ),
+            )
         };
-        entries.push(e);
+        let canonical = fs::canonicalize(self.path).map_err(read_error)?;
+        if let Some(start) = including.iter().position(|path| *path == canonical) {
+            let cycle: Vec<String> = including[start..]
+                .iter()
+                .chain(Some(&canonical))
+                .map(|path| path.display().to_string())
+                .collect();
+            return Err(ParseError::new(
+                self.line,
+                format!("Include cycle, line {}: {}", self.line + 1, cycle.join(" -> ")),
+            ));
+        }
+        if let Some(reason) = self.owner.and_then(|owner| untrusted(self.path, owner)) {
+            println!("Skipping crontab {}: {}", self.path.display(), reason);
+            return Ok(Vec::new());
+        }
+        let text = fs::read_to_string(self.path).map_err(read_error)?;
+        let document = Crontab::parse_with_seconds(&text, self.seconds);
+        let dir = self.path.parent().unwrap_or_else(|| Path::new(""));
+
+        including.push(canonical);
+        let entries = parse_lines(&document, dir, including, self.owner, env, defaults);
+        including.pop();
+        let mut entries = entries.map_err(|e| e.in_file(self.path))?;
+        for entry in &mut entries {
+            entry.source.path.get_or_insert_with(|| self.path.to_owned());
+        }
+        Ok(entries)
     }
-    return entries;
 }
 
-fn parse_cron_time(unit: &CronUnit, expr: &str, line: usize) -> Option<CronInterval> {
+fn parse_cron_time(
+    unit: &CronUnit,
+    expr: &str,
+    seed: u64,
+    line: usize,
+) -> Result<Option<CronInterval>, ParseError> {
     use CronInterval::*;
     let (min, max) = unit.bounds();
 
     //println!("{}", expr);
     // Handle comma-separated expressions "15,5-10"
     let mut time_points = Vec::new();
-    for sub_expr in expr.split(",") {
+    let mut days = Vec::new();
+    for sub_expr in expr.split(',') {
         // N.B. If any sub expression is * then we can just return Every
         if sub_expr == "*" {
-            return Some(Every);
+            return Ok(Some(Every));
+        }
+        // Parse days relative to the month "L", "LW", "15W", "5L" and "1#2"
+        else if let Some(day) = parse_day_spec(unit, sub_expr, line)? {
+            days.push(day);
+        }
+        // Parse "hashed" expressions "H", "H(0-29)", "H/15" and "H(0-29)/10"
+        else if sub_expr.starts_with('H') {
+            time_points.append(&mut parse_hashed(unit, sub_expr, seed, line)?);
         }
         // Parse "fraction" expressions "*/5"
         else if sub_expr.starts_with("*/") {
@@ -89,69 +398,94 @@ fn parse_cron_time(unit: &CronUnit, expr: &str, line: usize) -> Option<CronInter
                     time_points.append(&mut points);
                 }
                 Err(..) => {
-                    println!(
-                        "Parse error: Expression is not an integer, line {}: {}",
-                        line, sub_expr
-                    );
-                    std::process::exit(1);
+                    return Err(ParseError::new(
+                        line,
+                        format!(
+                            "Expression is not an integer, line {}: {}",
+                            line + 1,
+                            sub_expr
+                        ),
+                    ));
                 }
             };
         }
         // Parse "range" expression "30-45"
-        else if sub_expr.contains("-") {
-            let values: Vec<&str> = sub_expr.split("-").take(2).collect();
+        else if sub_expr.contains('-') {
+            let values: Vec<&str> = sub_expr.split('-').take(2).collect();
             // TODO handle panics here
             let start = values[0].parse::<u32>();
             let stop = values[1].parse::<u32>();
 
             if start.is_err() || stop.is_err() {
-                println!(
-                    "Parse error: Expression is not an integer, line {}: {}",
-                    line, sub_expr
-                );
-                std::process::exit(1);
+                return Err(ParseError::new(
+                    line,
+                    format!(
+                        "Expression is not an integer, line {}: {}",
+                        line + 1,
+                        sub_expr
+                    ),
+                ));
             }
 
             let start = start.unwrap();
             let stop = stop.unwrap();
 
             if is_out_of_bounds(start, min, max) {
-                println!(
-                    "Parse error: Value out of bounds, should be between {} and {}. line {}: {}",
-                    min, max, line, start
-                );
-                std::process::exit(1);
+                return Err(ParseError::new(
+                    line,
+                    format!(
+                        "Value out of bounds, should be between {} and {}. line {}: {}",
+                        min,
+                        max,
+                        line + 1,
+                        start
+                    ),
+                ));
             }
             if is_out_of_bounds(stop, min, max) {
-                println!(
-                    "Parse error: Value out of bounds, should be between {} and {}. line {}: {}",
-                    min, max, line, start
-                );
-                std::process::exit(1);
+                return Err(ParseError::new(
+                    line,
+                    format!(
+                        "Value out of bounds, should be between {} and {}. line {}: {}",
+                        min,
+                        max,
+                        line + 1,
+                        start
+                    ),
+                ));
             }
 
             if start == stop {
-                println!(
-                    "Parse error: Start and stop should not be the same value, line {}: {}",
-                    line, sub_expr
-                );
-                std::process::exit(1);
+                return Err(ParseError::new(
+                    line,
+                    format!(
+                        "Start and stop should not be the same value, line {}: {}",
+                        line + 1,
+                        sub_expr
+                    ),
+                ));
             }
 
             if start > stop {
-                println!(
-                    "Parse error: Start should not be before than stop, line {}: {}",
-                    line, sub_expr
-                );
-                std::process::exit(1);
+                return Err(ParseError::new(
+                    line,
+                    format!(
+                        "Start should not be before than stop, line {}: {}",
+                        line + 1,
+                        sub_expr
+                    ),
+                ));
             }
 
             if stop < start {
-                println!(
-                    "Parse error: Stop should not be after than start, line {}: {}",
-                    line, sub_expr
-                );
-                std::process::exit(1);
+                return Err(ParseError::new(
+                    line,
+                    format!(
+                        "Stop should not be after than start, line {}: {}",
+                        line + 1,
+                        sub_expr
+                    ),
+                ));
             }
 
             let mut points: Vec<u32> = (start..stop + 1).collect();
@@ -164,58 +498,835 @@ fn parse_cron_time(unit: &CronUnit, expr: &str, line: usize) -> Option<CronInter
                     time_points.push(n);
                 }
                 Ok(n) => {
-                    println!(
-                      "Parse error: Value out of bounds, should be between {} and {}. line {}: {}",
-                      min, max, line, n
-                  );
-                    std::process::exit(1);
+                    return Err(ParseError::new(
+                        line,
+                        format!(
+                            "Value out of bounds, should be between {} and {}. line {}: {}",
+                            min,
+                            max,
+                            line + 1,
+                            n
+                        ),
+                    ));
                 }
                 Err(..) => {
-                    println!(
-                        "Parse error: Expression is not an integer, line {}: {}",
-                        line, sub_expr
-                    );
-                    std::process::exit(1);
+                    return Err(ParseError::new(
+                        line,
+                        format!(
+                            "Expression is not an integer, line {}: {}",
+                            line + 1,
+                            sub_expr
+                        ),
+                    ));
                 }
             };
         }
     }
 
+    time_points.sort_unstable();
+    time_points.dedup();
+    if !days.is_empty() {
+        return Ok(Some(Calendar(time_points.into_iter().collect(), days)));
+    }
     if !time_points.is_empty() {
-        time_points.sort();
-        time_points.dedup();
-        return Some(Multiple(time_points));
+        if let CronUnit::Year = unit {
+            return Ok(Some(Years(time_points)));
+        }
+        return Ok(Some(Multiple(time_points.into_iter().collect())));
+    }
+
+    Ok(None)
+}
+
+/// Parse the Quartz style days that depend on the month, None if `expr` is
+/// not one of them
+fn parse_day_spec(unit: &CronUnit, expr: &str, line: usize) -> Result<Option<DaySpec>, ParseError> {
+    let number = |n: &str, min: u32, max: u32| match n.parse::<u32>() {
+        Ok(n) if n >= min && n <= max => Ok(n),
+        _ => Err(ParseError::new(
+            line,
+            format!(
+                "Value out of bounds, should be between {} and {}. line {}: {}",
+                min,
+                max,
+                line + 1,
+                expr
+            ),
+        )),
+    };
+    let spec = match unit {
+        CronUnit::DayOfMonth => match expr {
+            "L" => DaySpec::LastDay,
+            "LW" => DaySpec::LastWeekday,
+            _ => match expr.strip_suffix('W') {
+                Some(day) => DaySpec::NearestWeekday(number(day, 1, 31)?),
+                None => return Ok(None),
+            },
+        },
+        CronUnit::DayOfWeek => {
+            if let Some(dow) = expr.strip_suffix('L') {
+                return Ok(Some(DaySpec::LastOf(number(dow, 0, 6)?)));
+            }
+            let mut parts = expr.splitn(2, '#');
+            match (parts.next(), parts.next()) {
+                (Some(dow), Some(n)) => DaySpec::Nth(number(dow, 0, 6)?, number(n, 1, 5)?),
+                _ => return Ok(None),
+            }
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(spec))
+}
+
+/// Resolve a hashed expression to values that look random, but are the same
+/// every time for a given job on a given host. This spreads out jobs that
+/// share a schedule, across jobs and across hosts.
+fn parse_hashed(
+    unit: &CronUnit,
+    expr: &str,
+    seed: u64,
+    line: usize,
+) -> Result<Vec<u32>, ParseError> {
+    let (mut min, mut max) = unit.bounds();
+    // Keep hashed days within the length of every month
+    if let CronUnit::DayOfMonth = unit {
+        max = 28;
+    }
+
+    let mut rest = &expr[1..];
+    if let Some(range) = rest.strip_prefix('(') {
+        let invalid = || {
+            ParseError::new(
+                line,
+                format!(
+                    "Invalid range in hashed expression, line {}: {}",
+                    line + 1,
+                    expr
+                ),
+            )
+        };
+        let close = range.find(')').ok_or_else(invalid)?;
+        let values: Vec<Option<u32>> = range[..close].split('-').map(|v| v.parse().ok()).collect();
+        match values.as_slice() {
+            [Some(start), Some(stop)] if start < stop && *start >= min && *stop <= unit.max() => {
+                min = *start;
+                max = *stop;
+            }
+            _ => return Err(invalid()),
+        }
+        rest = range.get(close + 1..).unwrap_or_default();
+    }
+
+    let span = max - min + 1;
+    if rest.is_empty() {
+        return Ok(vec![min + (seed % span as u64) as u32]);
+    }
+    match rest.strip_prefix('/').map(str::parse::<u32>) {
+        Some(Ok(step)) if step > 0 => {
+            let start = min + (seed % std::cmp::min(step, span) as u64) as u32;
+            Ok((start..=max).step_by(step as usize).collect())
+        }
+        _ => Err(ParseError::new(
+            line,
+            format!("Invalid hashed expression, line {}: {}", line + 1, expr),
+        )),
+    }
+}
+
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    let result = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if result != 0 {
+        return String::new();
+    }
+    let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+/// A hash of the job, the host name and `salt` that stays the same across
+/// restarts and builds, unlike the hashers of std. This is 64 bit FNV-1a.
+pub fn job_hash(job: &str, salt: &str) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    let host = hostname();
+    for part in [host.as_str(), job, salt].iter() {
+        for byte in part.bytes().chain(std::iter::once(0)) {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+    hash
+}
+
+fn parse_special_expression(predicate: &str, line: usize) -> Result<&'static str, ParseError> {
+    match predicate {
+        "@yearly" | "@annually" => Ok("0 0 1 1 *"),
+        "@monthly" => Ok("0 0 1 * *"),
+        "@weekly" => Ok("0 0 * * 0"),
+        "@daily" | "@midnight" => Ok("0 0 * * *"),
+        "@hourly" => Ok("0 * * * *"),
+        _ => Err(ParseError::new(
+            line,
+            format!("Unknown scheduling: {}, line {}", predicate, line + 1),
+        )),
+    }
+}
+
+/// Parse the `90m` or `90m from 00:15` after `@every`
+fn parse_every(expr: &str, line: usize) -> Result<Every, ParseError> {
+    let words: Vec<&str> = expr.split_whitespace().collect();
+    let period = words.first().and_then(|period| parse_duration(period));
+    let anchor = match words.as_slice() {
+        [_] => Some(Anchor::LastStart(None)),
+        [_, "from", time] => NaiveTime::parse_from_str(time, "%H:%M")
+            .ok()
+            .map(Anchor::Daily),
+        _ => None,
+    };
+    match (period, anchor) {
+        (Some(period), Some(anchor)) if period >= Duration::seconds(1) => {
+            Ok(Every { period, anchor })
+        }
+        _ => Err(ParseError::new(
+            line,
+            format!(
+                "Expected `@every DURATION [from HH:MM]`, line {}: @every{}",
+                line + 1,
+                expr
+            ),
+        )),
+    }
+}
+
+/// Check that the day of month can occur in one of the months, when it is
+/// not combined with a day of week. "30 2" would never run otherwise.
+fn has_possible_day(schedule: &Schedule) -> bool {
+    const MONTH_LENGTHS: [u32; 12] = [31, 29, 31, 30, 31, 30, 31, 31, 30, 31, 30, 31];
+    let first_day = match &schedule.dom {
+        CronExpr(_, CronInterval::Multiple(days)) if schedule.dow.is_every() => {
+            days.first().unwrap_or(31)
+        }
+        CronExpr(_, CronInterval::Calendar(days, specs)) if schedule.dow.is_every() => {
+            let mut first = days.first().unwrap_or(31);
+            for spec in specs {
+                match spec {
+                    DaySpec::NearestWeekday(day) => first = std::cmp::min(first, *day),
+                    _ => return true,
+                }
+            }
+            first
+        }
+        _ => return true,
+    };
+    (1..=12).any(|month| {
+        schedule.month.contains(month) && MONTH_LENGTHS[month as usize - 1] >= first_day
+    })
+}
+
+/// Parse a comma separated list of `name(value)` options on top of `options`
+fn parse_options(
+    mut options: JobOptions,
+    text: &str,
+    line: usize,
+) -> Result<JobOptions, ParseError> {
+    for (name, value) in split_options(text) {
+        let valid = match name {
+            "catchup" => {
+                let mut parts = value.splitn(2, ':');
+                let catchup = match (parts.next(), parts.next().map(str::parse::<u32>)) {
+                    (Some("skip"), None) => Some(CatchUp::Skip),
+                    (Some("once"), None) => Some(CatchUp::Once),
+                    (Some("all"), None) => Some(CatchUp::All(DEFAULT_CATCHUP_LIMIT)),
+                    (Some("all"), Some(Ok(limit))) if limit > 0 => Some(CatchUp::All(limit)),
+                    _ => None,
+                };
+                catchup.map(|catchup| options.catchup = catchup).is_some()
+            }
+            "concurrency" => {
+                let concurrency = match value {
+                    "allow" => Some(Concurrency::Allow),
+                    "skip" => Some(Concurrency::Skip),
+                    "queue" => Some(Concurrency::Queue),
+                    "replace" => Some(Concurrency::Replace),
+                    _ => None,
+                };
+                concurrency
+                    .map(|concurrency| options.concurrency = concurrency)
+                    .is_some()
+            }
+            "timeout" if value == "none" => {
+                options.timeout = Some(Duration::zero());
+                true
+            }
+            "timeout" => match parse_duration(value) {
+                Some(timeout) if timeout > Duration::zero() => {
+                    options.timeout = Some(timeout);
+                    true
+                }
+                _ => false,
+            },
+            "attempts" => match value.parse::<u32>() {
+                Ok(attempts) if attempts > 0 => {
+                    options.retry.attempts = attempts;
+                    true
+                }
+                _ => false,
+            },
+            "retry_delay" => parse_duration(value)
+                .map(|delay| options.retry.delay = delay)
+                .is_some(),
+            "retry_backoff" => match value.parse::<f64>() {
+                Ok(backoff) if backoff >= 1.0 => {
+                    options.retry.backoff = backoff;
+                    true
+                }
+                _ => false,
+            },
+            "retry_jitter" => parse_duration(value)
+                .map(|jitter| options.retry.jitter = jitter)
+                .is_some(),
+            "retry_on" => {
+                let mut codes = Vec::new();
+                for code in value.split(',') {
+                    // Exit codes go up to 255, which also bounds the ranges
+                    let range: Vec<Option<u8>> = code.split('-').map(|c| c.parse().ok()).collect();
+                    match range.as_slice() {
+                        [Some(code)] => codes.push(i32::from(*code)),
+                        [Some(start), Some(end)] if start < end => {
+                            codes.extend(i32::from(*start)..=i32::from(*end))
+                        }
+                        _ => {
+                            codes.clear();
+                            break;
+                        }
+                    }
+                }
+                let valid = !codes.is_empty();
+                if valid {
+                    options.retry.exit_codes = codes;
+                }
+                valid
+            }
+            "after" if !value.is_empty() => {
+                options.after = Some(value.to_owned());
+                true
+            }
+            "group" if !value.is_empty() => {
+                options.group = Some(value.to_owned());
+                true
+            }
+            "start_delay" => match parse_duration(value) {
+                Some(delay) if delay > Duration::zero() => {
+                    options.start_delay = Some(delay);
+                    true
+                }
+                _ => false,
+            },
+            "random_delay" => match parse_duration(value) {
+                Some(delay) => {
+                    options.random_delay = Some(delay);
+                    true
+                }
+                None => false,
+            },
+            // Read by the document model, they change how entries are split
+            "seconds" | "year" | "multiline" => value == "on" || value == "off",
+            "start_hours" => {
+                let hours: Vec<Option<u32>> = value.split('-').map(|h| h.parse().ok()).collect();
+                match hours.as_slice() {
+                    [Some(start), Some(end)] if start < end && *end <= 24 => {
+                        options.start_hours = Some((*start, *end));
+                        true
+                    }
+                    _ => false,
+                }
+            }
+            _ => {
+                return Err(ParseError::new(
+                    line,
+                    format!("Unknown option `{}`, line {}", name, line + 1),
+                ));
+            }
+        };
+        if !valid {
+            return Err(ParseError::new(
+                line,
+                format!(
+                    "Invalid value for option `{}`, line {}: {}",
+                    name,
+                    line + 1,
+                    value
+                ),
+            ));
+        }
+    }
+    if !options.retry.is_bounded() {
+        return Err(ParseError::new(
+            line,
+            format!("Retries would wait longer than 1000 years, line {}", line + 1),
+        ));
+    }
+    Ok(options)
+}
+
+/// Hashed fields take their values from the command, there is none outside
+/// of an entry
+fn check_unhashed(expr: &str) -> Result<(), ParseError> {
+    if expr.contains('H') {
+        return Err(ParseError::new(
+            0,
+            format!("Hashed fields need the command of an entry: {}", expr),
+        ));
+    }
+    Ok(())
+}
+
+/// The options written after `&` in front of an entry, on top of the
+/// defaults
+impl FromStr for JobOptions {
+    type Err = ParseError;
+
+    fn from_str(text: &str) -> Result<JobOptions, ParseError> {
+        if text.is_empty() {
+            return Ok(JobOptions::default());
+        }
+        parse_options(JobOptions::default(), text, 0)
     }
+}
+
+impl FromStr for CronUnit {
+    type Err = ParseError;
+
+    fn from_str(name: &str) -> Result<CronUnit, ParseError> {
+        use CronUnit::*;
+        [Second, Minute, Hour, DayOfMonth, Month, DayOfWeek, Year]
+            .iter()
+            .find(|unit| unit.name() == name)
+            .cloned()
+            .ok_or_else(|| ParseError::new(0, format!("Unknown unit: {}", name)))
+    }
+}
+
+/// A field without its unit. Steps count from the start of the unit, so
+/// they are not accepted here, and the values decide which unit the days
+/// like "L" or "5#2" are read for.
+impl FromStr for CronInterval {
+    type Err = ParseError;
 
-    return None;
+    fn from_str(expr: &str) -> Result<CronInterval, ParseError> {
+        check_unhashed(expr)?;
+        if expr.contains('/') {
+            return Err(ParseError::new(
+                0,
+                format!("Steps need the unit of the field: {}", expr),
+            ));
+        }
+        let units = [
+            CronUnit::Minute,
+            CronUnit::DayOfMonth,
+            CronUnit::DayOfWeek,
+            CronUnit::Year,
+        ];
+        units
+            .iter()
+            .find_map(|unit| parse_cron_time(unit, expr, 0, 0).ok().flatten())
+            .ok_or_else(|| ParseError::new(0, format!("Not a valid field: {}", expr)))
+    }
 }
 
-fn parse_special_expression(expr: &str, line: usize) -> String {
-    if let Some(index) = expr.find(|c: char| c.is_whitespace()) {
-        let (predicate, cmd) = expr.split_at(index);
-        match predicate {
-            "@yearly" | "@annually" => return vec!["0 0 1 1 *", cmd].join(" "),
-            "@monthly" => return vec!["0 0 1 * *", cmd].join(" "),
-            "@weekly" => return vec!["0 0 * * 0", cmd].join(" "),
-            "@daily" | "@midnight" => return vec!["0 0 * * *", cmd].join(" "),
-            "@hourly" => return vec!["0 * * * *", cmd].join(" "),
+/// The unit followed by the field, "minute */15" for example
+impl FromStr for CronExpr {
+    type Err = ParseError;
+
+    fn from_str(text: &str) -> Result<CronExpr, ParseError> {
+        let mut words = text.split_whitespace();
+        let (unit, expr) = match (words.next(), words.next(), words.next()) {
+            (Some(unit), Some(expr), None) => (unit.parse::<CronUnit>()?, expr),
             _ => {
-                println!(
-                    "Parse error: Unknown scheduling: {}, line {}",
-                    predicate, line
-                );
-                std::process::exit(1);
+                return Err(ParseError::new(
+                    0,
+                    format!("Expected `UNIT FIELD`: {}", text),
+                ));
+            }
+        };
+        check_unhashed(expr)?;
+        match parse_cron_time(&unit, expr, 0, 0)? {
+            Some(interval) => Ok(CronExpr(unit, interval)),
+            None => Err(ParseError::new(
+                0,
+                format!("Could not match any expression: {}", expr),
+            )),
+        }
+    }
+}
+
+/// Whether `word` is a list of four digit years and ranges of them
+fn is_year_field(word: &str) -> bool {
+    let is_year = |year: &str| year.len() == 4 && year.bytes().all(|b| b.is_ascii_digit());
+    word.split(',').all(|part| part.splitn(2, '-').all(is_year))
+}
+
+/// The time fields of an entry, five of them or six with a leading seconds
+/// field, optionally followed by a year. `@daily` and the like are accepted
+/// too. Without a command after them, a last field of four digit years can
+/// only be a year field.
+impl FromStr for Schedule {
+    type Err = ParseError;
+
+    fn from_str(text: &str) -> Result<Schedule, ParseError> {
+        check_unhashed(text)?;
+        let fields: Vec<&str> = text.split_whitespace().collect();
+        let year = fields.len() > 5 && fields.last().is_some_and(|last| is_year_field(last));
+        let seconds = fields.len() - year as usize == 6;
+        let switches: Vec<&str> = [(seconds, "seconds(on)"), (year, "year(on)")]
+            .iter()
+            .filter(|(on, _)| *on)
+            .map(|(_, switch)| *switch)
+            .collect();
+        let line = match switches.is_empty() {
+            true => format!("{} -", fields.join(" ")),
+            false => format!("&{} {} -", switches.join(","), fields.join(" ")),
+        };
+        let mut entries = parse_crontab(&line)?;
+        match entries.pop() {
+            Some(entry) if entries.is_empty() && entry.every.is_none() && entry.startup.is_none() => {
+                Ok(entry.schedule)
             }
+            _ => Err(ParseError::new(
+                0,
+                format!("Expected the time fields of an entry: {}", text),
+            )),
         }
     }
+}
+
+/// A crontab with exactly one entry, variables and `!` defaults included
+impl FromStr for CronEntry {
+    type Err = ParseError;
 
-    println!(
-        "Parse error: Error parsing command after predicate, line {}: {}",
-        line, expr
-    );
-    std::process::exit(1);
+    fn from_str(text: &str) -> Result<CronEntry, ParseError> {
+        let mut entries = parse_crontab(text)?;
+        if entries.len() != 1 {
+            return Err(ParseError::new(
+                0,
+                format!("Expected a single entry, found {}", entries.len()),
+            ));
+        }
+        Ok(entries.remove(0))
+    }
 }
 
 fn is_out_of_bounds(x: u32, min: u32, max: u32) -> bool {
     !(x >= min && x <= max)
-}
\ No newline at end of file
+}
+
+/// Write a duration the way `parse_duration` reads it, "1h30m" for 90
+/// minutes. Fractions of a second are dropped.
+pub fn format_duration(duration: Duration) -> String {
+    let mut seconds = duration.num_seconds();
+    if seconds <= 0 {
+        return "0s".to_owned();
+    }
+    let mut text = String::new();
+    for (unit, length) in [('d', 86400), ('h', 3600), ('m', 60), ('s', 1)].iter() {
+        if seconds >= *length {
+            text.push_str(&format!("{}{}", seconds / length, unit));
+            seconds %= length;
+        }
+    }
+    text
+}
+
+/// Parse a duration such as "90s", "15m" or "1h30m"
+pub fn parse_duration(expr: &str) -> Option<Duration> {
+    let mut total: i64 = 0;
+    let mut digits = String::new();
+    for c in expr.trim().chars() {
+        if c.is_ascii_digit() {
+            digits.push(c);
+            continue;
+        }
+        let n = digits.parse::<i64>().ok()?;
+        digits.clear();
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            'w' => 7 * 86400,
+            _ => return None,
+        };
+        total = n.checked_mul(unit).and_then(|n| total.checked_add(n))?;
+    }
+    if !digits.is_empty() || expr.trim().is_empty() || total > MAX_DURATION_SECONDS {
+        return None;
+    }
+    Some(Duration::seconds(total))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("90s"), Some(Duration::seconds(90)));
+        assert_eq!(parse_duration("1h30m"), Some(Duration::minutes(90)));
+        assert_eq!(parse_duration("2d"), Some(Duration::days(2)));
+        assert_eq!(parse_duration(""), None);
+        assert_eq!(parse_duration("15"), None);
+        assert_eq!(format_duration(Duration::minutes(90)), "1h30m");
+        assert_eq!(format_duration(Duration::seconds(86401)), "1d1s");
+        assert_eq!(format_duration(Duration::zero()), "0s");
+        assert_eq!(parse_duration("m"), None);
+        assert_eq!(parse_duration("5y"), None);
+        assert_eq!(parse_duration("9999999999999999w"), None);
+        assert_eq!(parse_duration("99999999999999999999s"), None);
+        assert_eq!(parse_duration("52000w"), Some(Duration::weeks(52000)));
+        assert!(parse_crontab("&timeout(9999999999999999w) @daily a").is_err());
+    }
+
+    #[test]
+    fn errors() {
+        let error = parse_crontab("@daily a\n\n61 * * * * b\n").unwrap_err();
+        assert_eq!(error.line, 3);
+        assert_eq!(
+            error.to_string(),
+            "Parse error: Value out of bounds, should be between 0 and 59. line 3: 61"
+        );
+        assert_eq!(parse_crontab("&nope(1) @daily a").unwrap_err().line, 1);
+        assert_eq!(parse_crontab("@every 1x a").unwrap_err().line, 1);
+        assert!(parse_crontab("* * * *").is_err());
+    }
+
+    #[test]
+    fn options() {
+        let entries = parse_crontab(
+            "@daily a\n\
+             !catchup(once),random_delay(10m)\n\
+             !group(backup)\n\
+             @daily b\n\
+             &timeout(1h),attempts(3),retry_on(1,75-77),retry_backoff(1.5) @daily b\n\
+             &catchup(all:3),start_hours(3-22),concurrency(queue) @daily c\n",
+        )
+        .unwrap();
+        assert_eq!(entries[0].options, JobOptions::default());
+        assert_eq!(entries[1].options.group, Some(String::from("backup")));
+        assert_eq!(entries[1].options.catchup, CatchUp::Once);
+        assert_eq!(entries[1].options.random_delay, Some(Duration::minutes(10)));
+        assert_eq!(entries[1].options.timeout, None);
+        assert_eq!(entries[2].options.timeout, Some(Duration::hours(1)));
+        assert_eq!(entries[2].options.retry.attempts, 3);
+        assert_eq!(entries[2].options.retry.exit_codes, vec![1, 75, 76, 77]);
+        assert_eq!(entries[2].options.retry.backoff, 1.5);
+        assert_eq!(entries[3].options.catchup, CatchUp::All(3));
+        assert_eq!(entries[3].options.random_delay, Some(Duration::minutes(10)));
+        assert_eq!(entries[3].options.start_hours, Some((3, 22)));
+        assert_eq!(entries[3].options.concurrency, Concurrency::Queue);
+        assert_eq!(entries[3].cmd, "c");
+
+        let retry_on = |codes| parse_crontab(&format!("&retry_on({}) @daily a", codes));
+        assert_eq!(retry_on("0-255").unwrap()[0].options.retry.exit_codes.len(), 256);
+        assert!(retry_on("256").is_err());
+        assert!(retry_on("0-2000000000").is_err());
+        assert!(retry_on("-1").is_err());
+
+        assert!(parse_crontab("&attempts(40),retry_backoff(100) @daily a").is_err());
+        assert!(parse_crontab("!attempts(40)\n&retry_backoff(100) @daily a").is_err());
+        assert!(parse_crontab("&attempts(4000000000),retry_backoff(1) @daily a").is_ok());
+    }
+
+    #[test]
+    fn reboot() {
+        let entries =
+            parse_crontab("@reboot a\n@reboot+5m b\n&after(test -e /run/x) @reboot c\n").unwrap();
+        assert_eq!(entries[0].startup, Some(Duration::zero()));
+        assert_eq!(entries[1].startup, Some(Duration::minutes(5)));
+        assert_eq!(entries[1].cmd, "b");
+        assert_eq!(
+            entries[2].options.after,
+            Some(String::from("test -e /run/x"))
+        );
+
+        let error = parse_crontab("@reboot a\n&after(true) @daily b\n").unwrap_err();
+        assert_eq!(error.line, 2);
+        assert!(error.message.starts_with("The after option only applies to @reboot"));
+        assert!(parse_crontab("&after(true) @every 1h b\n").is_err());
+    }
+
+    #[test]
+    fn hashed() {
+        let crontab = "H H(2-5) H * H/2 a\nH(10-19)/3 * * * * a\nH H(2-5) H * H/2 b\n";
+        let entries = parse_crontab(crontab).unwrap();
+        let again = parse_crontab(crontab).unwrap();
+        let values = |expr: &CronExpr| match expr {
+            CronExpr(_, CronInterval::Multiple(v)) => v.iter().collect::<Vec<u32>>(),
+            _ => panic!("expected values"),
+        };
+
+        let a = &entries[0].schedule;
+        assert_eq!(values(&a.minute), values(&again[0].schedule.minute));
+        assert_eq!(values(&a.hour), values(&again[0].schedule.hour));
+        assert!(values(&a.minute)[0] <= 59);
+        assert!((2..=5).contains(&values(&a.hour)[0]));
+        assert!((1..=28).contains(&values(&a.dom)[0]));
+        let dow = values(&a.dow);
+        assert!(dow.len() >= 3 && dow.windows(2).all(|w| w[1] - w[0] == 2));
+
+        let minutes = values(&entries[1].schedule.minute);
+        assert!(minutes.len() >= 3 && minutes.iter().all(|m| (10..=19).contains(m)));
+
+        // Different jobs get different values, at least for some fields
+        let b = &entries[2].schedule;
+        assert!(
+            values(&a.minute) != values(&b.minute)
+                || values(&a.hour) != values(&b.hour)
+                || values(&a.dom) != values(&b.dom)
+        );
+
+        let error = parse_crontab("H(0-29 * * * * a").unwrap_err();
+        assert!(error.message.starts_with("Invalid range in hashed expression"));
+        assert!(parse_crontab("H(0-29)x * * * * a").is_err());
+    }
+
+    #[test]
+    fn display_round_trip() {
+        let crontab = "&catchup(all:3),timeout(1h30m),retry_on(1,2,3),year(on) */15 9-17 L,15W * 1-5 2030 a\n\
+                       &seconds(on) */10 * * * * * b\n\
+                       0 12 * * 5L,1#2 c\n\
+                       MAILTO=ops\n\
+                       GREETING=\" hi \"\n\
+                       @every 90m from 00:15 d\n\
+                       @reboot+5m e\n";
+        for entry in parse_crontab(crontab).unwrap() {
+            let text = entry.to_string();
+            let again: CronEntry = text.parse().unwrap();
+            assert_eq!(again.to_string(), text);
+            assert_eq!(again.schedule, entry.schedule);
+            assert_eq!(again.every, entry.every);
+            assert_eq!(again.startup, entry.startup);
+            assert_eq!(again.env, entry.env);
+            assert_eq!(again.options, entry.options);
+        }
+        let entries = parse_crontab(crontab).unwrap();
+        let lines: Vec<String> = entries.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            lines,
+            vec![
+                "&catchup(all:3),timeout(1h30m),retry_on(1,2,3),year(on) 0,15,30,45 9-17 L,15W * 1-5 2030 a",
+                "&seconds(on) 0,10,20,30,40,50 * * * * * b",
+                "0 12 * * 5L,1#2 c",
+                "MAILTO=ops\nGREETING=\" hi \"\n@every 1h30m from 00:15 d",
+                "MAILTO=ops\nGREETING=\" hi \"\n@reboot+5m e",
+            ]
+        );
+
+        let expr: CronExpr = "day_of_week 1-5,0L".parse().unwrap();
+        assert_eq!(expr.to_string(), "day_of_week 1-5,0L");
+        assert_eq!("0,L".parse::<CronInterval>().unwrap_err().line, 1);
+        assert_eq!("2030-2032".parse::<CronInterval>().unwrap(), CronInterval::Years(vec![2030, 2031, 2032]));
+        assert!("*/5".parse::<CronInterval>().is_err());
+        assert!("H * * * *".parse::<Schedule>().is_err());
+        let schedule: Schedule = "30 */20 * * * * 2031".parse().unwrap();
+        assert_eq!(schedule.to_string(), "30 0,20,40 * * * * 2031");
+        assert_eq!("@daily".parse::<Schedule>().unwrap().to_string(), "0 0 * * *");
+        assert!("@reboot".parse::<Schedule>().is_err());
+        assert!("".parse::<CronEntry>().is_err());
+        assert!("@daily a\n@daily b".parse::<CronEntry>().is_err());
+    }
+
+    #[test]
+    fn multiline_commands() {
+        let crontab = "* * * * * a \\\n\
+                       !multiline(on)\n\
+                       0 3 * * * tar czf /backup.tgz \\\n    /etc\n\
+                       @daily <<END\ncd /srv\n./backup.sh\nEND\n\
+                       @daily mysql <<'SQL'\nselect 1;\nSQL\n\
+                       @weekly e\n";
+        let entries = parse_crontab(crontab).unwrap();
+        let cmds: Vec<&str> = entries.iter().map(|e| e.cmd()).collect();
+        assert_eq!(
+            cmds,
+            vec![
+                "a \\",
+                "tar czf /backup.tgz     /etc",
+                "cd /srv\n./backup.sh",
+                "mysql <<'SQL'\nselect 1;\nSQL",
+                "e"
+            ]
+        );
+        let lines: Vec<usize> = entries.iter().map(|e| e.source().line).collect();
+        assert_eq!(lines, vec![1, 3, 5, 9, 12]);
+
+        let text = entries[2].to_string();
+        assert_eq!(text, "&multiline(on) 0 0 * * * <<END\ncd /srv\n./backup.sh\nEND");
+        assert_eq!(text.parse::<CronEntry>().unwrap().cmd(), entries[2].cmd());
+        let mut entry = entries[2].clone();
+        entry.cmd = String::from("a\nEND");
+        assert!(entry.to_string().ends_with(" <<END_\na\nEND\nEND_"));
+
+        let error = |crontab| parse_crontab(crontab).unwrap_err();
+        assert!(error("!multiline(on)\n@daily <<END\necho\n").message.starts_with("Missing `END`"));
+        assert_eq!(error("!multiline(on)\n@daily <<END\nEND\n").line, 2);
+        assert_eq!(error("!multiline(on)\n@daily <<END\nx\nEND\n61 * * * * y").line, 5);
+        assert_eq!(error("!multiline(maybe)").line, 1);
+    }
+
+    #[test]
+    fn includes() {
+        let dir = TempDir::new("include");
+        for sub in &["shared", "hosts", "loop"] {
+            fs::create_dir_all(dir.join(sub)).unwrap();
+        }
+        let files = [
+            ("shared/base", "MAILTO=shared\n!timeout(5m)\n@daily backup\n@include ../hosts/*.cron\n"),
+            ("hosts/a.cron", "@hourly a\n"),
+            ("hosts/b.cron", "MAILTO=b\n@hourly b\n"),
+            ("hosts/.c.cron", "@hourly c\n"),
+            ("loop/x", "@include y\n"),
+            ("loop/y", "@include x\n"),
+            ("bad", "\n61 * * * * x\n"),
+        ];
+        for (name, text) in &files {
+            fs::write(dir.join(name), text).unwrap();
+        }
+        let parse = |text| parse_document_in(&Crontab::parse(text), &dir.join("crontab"));
+
+        let entries = parse("PATH=/bin\n@include shared/base\n@reboot after\n").unwrap();
+        let cmds: Vec<&str> = entries.iter().map(|e| e.cmd()).collect();
+        assert_eq!(cmds, vec!["backup", "a", "b", "after"]);
+        assert_eq!(entries[0].var("PATH"), Some("/bin"));
+        assert_eq!(entries[0].var("MAILTO"), Some("shared"));
+        assert_eq!(entries[1].options.timeout, Some(Duration::minutes(5)));
+        assert_eq!(entries[2].var("MAILTO"), Some("b"));
+        assert_eq!(entries[3].var("MAILTO"), None);
+        assert_eq!(entries[3].options.timeout, JobOptions::default().timeout);
+        assert_eq!(entries[0].source().to_string(), format!("{}:3", dir.join("shared/base").display()));
+        assert_eq!(entries[1].source().path, Some(dir.join("shared/../hosts/a.cron")));
+        assert_eq!(entries[3].source().path, None);
+
+        let error = parse("@include loop/x").unwrap_err();
+        assert_eq!((error.path, error.line), (Some(dir.join("loop/y")), 1));
+        assert!(error.message.starts_with("Include cycle"));
+        let error = parse("# bad\n@include bad").unwrap_err();
+        assert_eq!((error.path, error.line), (Some(dir.join("bad")), 2));
+        let error = parse("@include missing").unwrap_err();
+        assert_eq!((error.path, error.line), (Some(dir.join("crontab")), 1));
+        assert!(error.message.starts_with("Could not include"));
+        assert!(parse("@include none/*.cron").unwrap().is_empty());
+        assert!(parse_crontab("@include").is_err());
+
+        // Crontabs that others can write to are not included for an owner
+        use std::os::unix::fs::PermissionsExt;
+        let shared = dir.join("hosts/b.cron");
+        fs::set_permissions(&shared, fs::Permissions::from_mode(0o666)).unwrap();
+        let uid = unsafe { libc::geteuid() };
+        let crontab = Crontab::parse("@include hosts/*.cron
+");
+        let entries = parse_document_owned(&crontab, &dir.join("crontab"), uid).unwrap();
+        let cmds: Vec<&str> = entries.iter().map(|e| e.cmd()).collect();
+        assert_eq!(cmds, vec!["a"]);
+        let entries = parse_document_owned(&crontab, &dir.join("crontab"), uid + 1).unwrap();
+        assert!(entries.is_empty());
+    }
+}
@@ -0,0 +1,785 @@
+use std::io;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time;
+
+use chrono::prelude::*;
+use chrono::Duration;
+
+use crate::clock::random_duration;
+use crate::history::{History, Record};
+use crate::limits::Limits;
+use crate::mail::Mailer;
+use crate::options::{Concurrency, Retry};
+use crate::entry::{CronEntry, Job};
+
+/// How long a run gets to exit after SIGTERM before it is killed
+pub(crate) const KILL_GRACE_SECONDS: u64 = 10;
+
+/// The exit code recorded for a closure that panicked, the same as for a
+/// Rust program that panics
+pub(crate) const PANIC_EXIT_CODE: i32 = 101;
+
+/// The `PATH` of jobs that run as a user, unless their crontab sets one
+pub(crate) const USER_PATH: &str = "/usr/bin:/bin";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum RunId {
+    Process(u32),
+    Closure(u64),
+}
+
+/// A run started by either scheduler, `Running` keeps track of it by its id
+pub(crate) trait Started {
+    fn id(&self) -> RunId;
+}
+
+/// A run that was started, either a process or a closure on its own thread
+/// that reports whether it returned without panicking
+enum Run {
+    Process(Child),
+    Closure(u64, Receiver<bool>),
+}
+
+impl Started for Run {
+    fn id(&self) -> RunId {
+        match self {
+            Run::Process(child) => RunId::Process(child.id()),
+            Run::Closure(id, _) => RunId::Closure(*id),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct RunningState {
+    runs: Vec<RunId>,
+    // Runs that were admitted but are not spawned yet
+    starting: usize,
+    next_closure: u64,
+    // Tickets keep queued runs in the order they were due
+    next_ticket: u64,
+    serving: u64,
+    cancelled: bool,
+}
+
+impl RunningState {
+    fn processes(&self) -> Vec<u32> {
+        let pids = self.runs.iter().filter_map(|run| match run {
+            RunId::Process(pid) => Some(*pid),
+            RunId::Closure(_) => None,
+        });
+        pids.collect()
+    }
+
+    fn has_closures(&self) -> bool {
+        self.runs.iter().any(|run| matches!(run, RunId::Closure(_)))
+    }
+
+    /// Whether no run is going or about to start
+    fn idle(&self) -> bool {
+        self.runs.is_empty() && self.starting == 0
+    }
+
+    /// Count a run as going until its `Admitted` is spawned or dropped
+    fn admit(&mut self) {
+        self.starting += 1;
+    }
+
+    fn take_ticket(&mut self) -> u64 {
+        self.next_ticket += 1;
+        self.next_ticket - 1
+    }
+
+    /// Whether the queued run with `ticket` may start, it is next in line
+    /// and nothing else runs. If so it is admitted right away, so that the
+    /// next ticket is only served once it has run.
+    fn serve(&mut self, ticket: u64) -> bool {
+        let turn = self.serving == ticket && self.idle();
+        if turn {
+            self.serving += 1;
+            self.admit();
+        }
+        turn
+    }
+
+    fn spawn<R: Started>(&mut self, spawn: impl FnOnce() -> io::Result<R>) -> io::Result<R> {
+        let run = spawn();
+        if let Ok(run) = &run {
+            self.runs.push(run.id());
+        }
+        run
+    }
+}
+
+/// The runs of a job that are still going. Every process is started in its
+/// own process group, with the group id being its pid. Closures cannot be
+/// stopped, so a closure that times out counts as running until it returns,
+/// and `replace` waits for closures instead of killing them.
+#[derive(Clone, Debug, Default)]
+pub struct Running {
+    inner: Arc<(Mutex<RunningState>, Condvar)>,
+    #[cfg(feature = "tokio")]
+    changed: Arc<tokio::sync::Notify>,
+}
+
+impl Running {
+    pub fn pids(&self) -> Vec<u32> {
+        self.inner.0.lock().unwrap().processes()
+    }
+
+    /// How many runs are still going, processes and closures
+    pub fn count(&self) -> usize {
+        self.inner.0.lock().unwrap().runs.len()
+    }
+
+    pub(crate) fn closure_id(&self) -> u64 {
+        let mut state = self.inner.0.lock().unwrap();
+        state.next_closure += 1;
+        state.next_closure
+    }
+
+    /// Wait until `policy` allows a new run, None if the run is skipped
+    fn admit(&self, policy: Concurrency) -> Option<Admitted> {
+        let (lock, cvar) = &*self.inner;
+        let mut state = lock.lock().unwrap();
+        match policy {
+            Concurrency::Allow => state.admit(),
+            Concurrency::Skip => {
+                if !state.idle() {
+                    return None;
+                }
+                state.admit();
+            }
+            Concurrency::Queue => {
+                let ticket = state.take_ticket();
+                while !state.serve(ticket) {
+                    state = cvar.wait(state).unwrap();
+                }
+            }
+            Concurrency::Replace => {
+                // Runs about to start are replaced once they have
+                while state.starting > 0 {
+                    state = cvar.wait(state).unwrap();
+                }
+                let pids = state.processes();
+                drop(state);
+                for pid in pids {
+                    self.terminate(pid);
+                }
+                state = lock.lock().unwrap();
+                while state.has_closures() {
+                    state = cvar.wait(state).unwrap();
+                }
+                state.admit();
+            }
+        }
+        Some(Admitted(self.clone()))
+    }
+
+    /// Interrupt pending retries of the job
+    pub fn cancel(&self) {
+        let (lock, cvar) = &*self.inner;
+        lock.lock().unwrap().cancelled = true;
+        cvar.notify_all();
+    }
+
+    /// Sleep for `duration`, returns false if the job was cancelled meanwhile
+    fn sleep(&self, duration: Duration) -> bool {
+        let (lock, cvar) = &*self.inner;
+        let state = lock.lock().unwrap();
+        let (state, _) = cvar
+            .wait_timeout_while(state, duration.to_std().unwrap_or_default(), |state| {
+                !state.cancelled
+            })
+            .unwrap();
+        !state.cancelled
+    }
+
+    pub(crate) fn finished(&self, run: RunId) {
+        self.inner.0.lock().unwrap().runs.retain(|r| *r != run);
+        self.notify();
+    }
+
+    /// Wake up everything waiting for the state to change
+    fn notify(&self) {
+        self.inner.1.notify_all();
+        #[cfg(feature = "tokio")]
+        self.changed.notify_waiters();
+    }
+
+    /// Send SIGTERM to the process group of a run, and SIGKILL if it is still
+    /// running after the grace period
+    pub fn terminate(&self, pid: u32) {
+        let (lock, cvar) = &*self.inner;
+        signal_group(pid, libc::SIGTERM);
+        let grace = time::Duration::from_secs(KILL_GRACE_SECONDS);
+        let state = lock.lock().unwrap();
+        let (_state, timeout) = cvar
+            .wait_timeout_while(state, grace, |state| {
+                state.runs.contains(&RunId::Process(pid))
+            })
+            .unwrap();
+        if timeout.timed_out() {
+            signal_group(pid, libc::SIGKILL);
+        }
+    }
+}
+
+/// The same waits for the tokio scheduler, without blocking the thread
+#[cfg(feature = "tokio")]
+impl Running {
+    async fn wait_for(&self, mut done: impl FnMut(&mut RunningState) -> bool) {
+        loop {
+            // Created before checking, so no wakeup is missed
+            let changed = self.changed.notified();
+            let done = done(&mut self.inner.0.lock().unwrap());
+            if done {
+                return;
+            }
+            changed.await;
+        }
+    }
+
+    /// See `admit`
+    pub(crate) async fn admit_async(&self, policy: Concurrency) -> Option<Admitted> {
+        match policy {
+            Concurrency::Allow | Concurrency::Skip => {}
+            Concurrency::Queue => {
+                let ticket = self.inner.0.lock().unwrap().take_ticket();
+                self.wait_for(|state| state.serve(ticket)).await;
+                return Some(Admitted(self.clone()));
+            }
+            Concurrency::Replace => {
+                self.wait_for(|state| state.starting == 0).await;
+                let pids = self.pids();
+                for pid in pids {
+                    self.terminate_async(pid).await;
+                }
+                self.wait_for(|state| !state.has_closures()).await;
+            }
+        }
+        let mut state = self.inner.0.lock().unwrap();
+        if policy == Concurrency::Skip && !state.idle() {
+            return None;
+        }
+        state.admit();
+        Some(Admitted(self.clone()))
+    }
+
+    /// See `terminate`
+    async fn terminate_async(&self, pid: u32) {
+        signal_group(pid, libc::SIGTERM);
+        let exited = self.wait_for(|state| !state.runs.contains(&RunId::Process(pid)));
+        let grace = time::Duration::from_secs(KILL_GRACE_SECONDS);
+        if tokio::time::timeout(grace, exited).await.is_err() {
+            signal_group(pid, libc::SIGKILL);
+        }
+    }
+}
+
+/// A run the concurrency policy let start. It counts as going, so that the
+/// policy holds while it waits for a slot, until it is spawned or dropped.
+pub(crate) struct Admitted(Running);
+
+impl Admitted {
+    /// Start the run with `spawn`, which is called with the state of the
+    /// job locked
+    pub(crate) fn spawn<R: Started>(self, spawn: impl FnOnce() -> io::Result<R>) -> io::Result<R> {
+        // Bound first, so that the lock is let go before `drop` takes it
+        let run = self.0.inner.0.lock().unwrap().spawn(spawn);
+        run
+    }
+}
+
+impl Drop for Admitted {
+    fn drop(&mut self) {
+        self.0.inner.0.lock().unwrap().starting -= 1;
+        self.0.notify();
+    }
+}
+
+pub(crate) fn signal_group(pgid: u32, signal: libc::c_int) {
+    unsafe {
+        libc::kill(-(pgid as libc::pid_t), signal);
+    }
+}
+
+/// `script` run by the shell of `entry` with its variables, as its user and
+/// in its home directory, or in / if the user has none. Jobs of users get
+/// none of the variables of crust, only `USER_PATH` and their own.
+pub(crate) fn shell_command(entry: &CronEntry, script: &str) -> Command {
+    let mut command = Command::new(entry.shell());
+    command.arg("-c").arg(script);
+    if let Some(user) = &entry.user {
+        command.env_clear().env("PATH", USER_PATH);
+        let home = if user.home.is_dir() { user.home.as_path() } else { Path::new("/") };
+        command.current_dir(home);
+        if let Some(switch) = user.switch() {
+            // Safe, the closure only makes system calls
+            unsafe {
+                command.pre_exec(switch);
+            }
+        }
+    }
+    command.envs(entry.env.iter().cloned());
+    command
+}
+
+/// The command of a run of `entry`, in a process group of its own and with
+/// its output going to a file of `history` that is noted in `record`
+pub(crate) fn job_command(
+    entry: &CronEntry,
+    cmd: &str,
+    history: &History,
+    record: &mut Record,
+) -> Command {
+    let mut command = shell_command(entry, cmd);
+    command.process_group(0);
+    match history.output_file(&record.start) {
+        Ok((path, file)) => {
+            if let Ok(stderr) = file.try_clone() {
+                command.stderr(Stdio::from(stderr));
+            }
+            command.stdout(Stdio::from(file));
+            record.output_path = Some(path);
+        }
+        Err(e) => println!("Error creating output file for `{}`: {}", entry.cmd, e),
+    }
+    command
+}
+
+/// Run `entry` on a separate thread and record the run in `history` once
+/// the command has finished, retrying it if it failed. The output of each
+/// attempt is mailed with `mailer`.
+pub fn run_job(
+    entry: &CronEntry,
+    scheduled: DateTime<Local>,
+    history: &History,
+    limits: &Limits,
+    mailer: &Mailer,
+    running: &Running,
+) -> JoinHandle<()> {
+    let entry = entry.clone();
+    let history = history.clone();
+    let limits = limits.clone();
+    let mailer = mailer.clone();
+    let running = running.clone();
+    thread::spawn(move || {
+        let mut attempt = 1;
+        loop {
+            let record = match execute(&entry, scheduled, attempt, &history, &limits, &running) {
+                Some(record) => record,
+                None => {
+                    println!("Skipping: `{}`, the previous run is still going", entry.cmd);
+                    return;
+                }
+            };
+            report(&entry, &record, &history, &mailer);
+            let delay = match retry_delay(&entry, attempt, &record) {
+                Some(delay) => delay,
+                None => return,
+            };
+            attempt += 1;
+            if !running.sleep(delay) {
+                return;
+            }
+        }
+    })
+}
+
+/// How long to wait before the attempt after `attempt` of a run that ended
+/// with `record`, None if it is not retried
+pub(crate) fn retry_delay(entry: &CronEntry, attempt: u32, record: &Record) -> Option<Duration> {
+    let retry = &entry.options.retry;
+    if attempt >= retry.attempts || !should_retry(retry, record) {
+        return None;
+    }
+    let delay = retry.delay(attempt) + random_duration(retry.jitter);
+    if !retry_fits(entry, Local::now(), delay) {
+        println!("Not retrying: `{}`, the next run is due first", entry.cmd);
+        return None;
+    }
+    println!(
+        "Retrying: `{}` in {}s, attempt {} of {}",
+        entry.cmd,
+        delay.num_seconds(),
+        attempt + 1,
+        retry.attempts
+    );
+    Some(delay)
+}
+
+/// Whether a retry `delay` after `now` comes before the next scheduled run
+/// of `entry`. Catch-up runs were scheduled in the past, so the next run is
+/// looked up from `now` rather than from the run that failed.
+fn retry_fits(entry: &CronEntry, now: DateTime<Local>, delay: Duration) -> bool {
+    if entry.startup.is_some() {
+        return true;
+    }
+    let next = entry.next_execution(now + Duration::seconds(1));
+    match now.checked_add_signed(delay) {
+        Some(retry) => next.is_none_or(|next| retry < next),
+        None => next.is_none(),
+    }
+}
+
+fn should_retry(retry: &Retry, record: &Record) -> bool {
+    match record.exit_code {
+        Some(0) => false,
+        Some(code) => retry.exit_codes.is_empty() || retry.exit_codes.contains(&code),
+        None => record.timed_out && retry.exit_codes.is_empty(),
+    }
+}
+
+/// The record of an attempt at a run, before it starts
+pub(crate) fn new_record(
+    entry: &CronEntry,
+    scheduled: DateTime<Local>,
+    attempt: u32,
+    queue_wait: Duration,
+) -> Record {
+    Record {
+        job: entry.job_name(),
+        scheduled,
+        start: Local::now(),
+        end: Local::now(),
+        exit_code: None,
+        signal: None,
+        output_path: None,
+        output_size: 0,
+        timed_out: false,
+        attempt,
+        queue_wait_ms: queue_wait.num_milliseconds() as u64,
+    }
+}
+
+pub(crate) fn save_last_run(entry: &CronEntry, history: &History, time: DateTime<Local>) {
+    if let Err(e) = history.set_last_run(&entry.job_name(), time) {
+        println!("Error saving last run of `{}`: {}", entry.cmd, e);
+    }
+}
+
+/// Note in `record` how the process of a run exited
+pub(crate) fn process_exited(
+    entry: &CronEntry,
+    record: &mut Record,
+    status: io::Result<ExitStatus>,
+) {
+    match status {
+        Ok(status) => {
+            record.exit_code = status.code();
+            record.signal = status.signal();
+        }
+        Err(e) => println!("Error waiting for `{}`: {}", entry.cmd, e),
+    }
+}
+
+/// Note in `record` whether the closure of a run returned without
+/// panicking, None if it timed out
+pub(crate) fn closure_returned(record: &mut Record, returned: Option<bool>) {
+    match returned {
+        Some(true) => record.exit_code = Some(0),
+        Some(false) => record.exit_code = Some(PANIC_EXIT_CODE),
+        None => record.timed_out = true,
+    }
+}
+
+/// Complete the record of a run that ended
+pub(crate) fn finish_record(record: &mut Record) {
+    record.end = Local::now();
+    if let Some(path) = &record.output_path {
+        record.output_size = std::fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+    }
+}
+
+/// Log how a run ended, add it to `history` and mail its output
+pub(crate) fn report(entry: &CronEntry, record: &Record, history: &History, mailer: &Mailer) {
+    match (record.exit_code, record.signal) {
+        _ if record.timed_out => println!("Finished: `{}` timed out", entry.cmd),
+        (Some(code), _) => println!("Finished: `{}` with exit code {}", entry.cmd, code),
+        (None, Some(signal)) => println!("Finished: `{}` killed by signal {}", entry.cmd, signal),
+        (None, None) => {}
+    }
+    if let Err(e) = history.append(record) {
+        println!("Error writing history for `{}`: {}", entry.cmd, e);
+    }
+    if let Err(e) = mailer.send(entry, record) {
+        println!("Error mailing the output of `{}`: {}", entry.cmd, e);
+    }
+}
+
+fn execute(
+    entry: &CronEntry,
+    scheduled: DateTime<Local>,
+    attempt: u32,
+    history: &History,
+    limits: &Limits,
+    running: &Running,
+) -> Option<Record> {
+    let admitted = running.admit(entry.options.concurrency)?;
+    let queued = Local::now();
+    let _slot = limits.acquire(entry.options.group.as_deref());
+    let mut record = new_record(entry, scheduled, attempt, Local::now() - queued);
+
+    // Taken before starting, `spawn` runs with the state of `running` locked
+    let closure_id = running.closure_id();
+    let run = admitted.spawn(|| {
+        record.start = Local::now();
+        let cmd = match &*entry.job {
+            Job::Shell(cmd) => cmd,
+            Job::Fn(_) => {
+                let job = entry.job.clone();
+                let running = running.clone();
+                let (done, returned) = channel();
+                thread::spawn(move || {
+                    if let Job::Fn(f) = &*job {
+                        let ok = panic::catch_unwind(AssertUnwindSafe(f)).is_ok();
+                        // Even after a timeout, the run only ends here
+                        running.finished(RunId::Closure(closure_id));
+                        let _ = done.send(ok);
+                    }
+                });
+                return Ok(Run::Closure(closure_id, returned));
+            }
+        };
+        job_command(entry, cmd, history, &mut record).spawn().map(Run::Process)
+    });
+
+    save_last_run(entry, history, scheduled);
+    let timeout = entry.options.timeout.filter(|t| *t > Duration::zero());
+    match run {
+        Ok(Run::Process(mut child)) => {
+            let (done, finished) = channel::<()>();
+            let watchdog = timeout.map(|timeout| {
+                let running = running.clone();
+                let pid = child.id();
+                thread::spawn(move || match finished.recv_timeout(timeout.to_std().unwrap()) {
+                    Err(RecvTimeoutError::Timeout) => {
+                        running.terminate(pid);
+                        true
+                    }
+                    _ => false,
+                })
+            });
+
+            let status = child.wait();
+            running.finished(RunId::Process(child.id()));
+            drop(done);
+            if let Some(watchdog) = watchdog {
+                record.timed_out = watchdog.join().unwrap_or(false);
+            }
+            process_exited(entry, &mut record, status);
+        }
+        Ok(Run::Closure(_, returned)) => {
+            let returned = match timeout {
+                Some(timeout) => returned.recv_timeout(timeout.to_std().unwrap()),
+                None => returned.recv().map_err(RecvTimeoutError::from),
+            };
+            let returned = match returned {
+                Ok(ok) => Some(ok),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => Some(false),
+            };
+            closure_returned(&mut record, returned);
+        }
+        Err(e) => println!("Error running `{}`: {}", entry.cmd, e),
+    }
+    finish_record(&mut record);
+    Some(record)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn sleep_command() -> io::Result<Run> {
+        Command::new("sleep").arg("30").process_group(0).spawn().map(Run::Process)
+    }
+
+    fn start(running: &Running, policy: Concurrency) -> Option<io::Result<Run>> {
+        running.admit(policy).map(|admitted| admitted.spawn(sleep_command))
+    }
+
+    fn pid(run: &Run) -> u32 {
+        match run.id() {
+            RunId::Process(pid) => pid,
+            RunId::Closure(_) => panic!("expected a process"),
+        }
+    }
+
+    fn reap(running: &Running, run: Run) -> JoinHandle<ExitStatus> {
+        let mut child = match run {
+            Run::Process(child) => child,
+            Run::Closure(..) => panic!("expected a process"),
+        };
+        let running = running.clone();
+        thread::spawn(move || {
+            let status = child.wait().unwrap();
+            running.finished(RunId::Process(child.id()));
+            status
+        })
+    }
+
+    #[test]
+    fn overlap_policies() {
+        let running = Running::default();
+        // A run counts as going once it is admitted, before it is spawned
+        let admitted = running.admit(Concurrency::Allow).unwrap();
+        assert!(start(&running, Concurrency::Skip).is_none());
+        drop(admitted);
+        let first = start(&running, Concurrency::Allow).unwrap().unwrap();
+        let first = reap(&running, first);
+        assert!(start(&running, Concurrency::Skip).is_none());
+
+        let second = start(&running, Concurrency::Replace).unwrap().unwrap();
+        assert_eq!(first.join().unwrap().signal(), Some(libc::SIGTERM));
+        assert_eq!(running.pids(), vec![pid(&second)]);
+        let second_pid = pid(&second);
+        let second = reap(&running, second);
+
+        let queued = {
+            let running = running.clone();
+            thread::spawn(move || start(&running, Concurrency::Queue))
+        };
+        thread::sleep(time::Duration::from_millis(100));
+        assert_eq!(running.pids(), vec![second_pid]);
+        running.terminate(second_pid);
+        second.join().unwrap();
+
+        let third = queued.join().unwrap().unwrap().unwrap();
+        assert_eq!(running.pids(), vec![pid(&third)]);
+        let third_pid = pid(&third);
+        let third = reap(&running, third);
+        running.terminate(third_pid);
+        third.join().unwrap();
+        assert!(running.pids().is_empty());
+    }
+
+    #[test]
+    fn slots_after_admission() {
+        use crate::parser::parse_crontab;
+        use std::collections::HashMap;
+
+        let dir = TempDir::new("slots");
+        let history = History::new(dir.path());
+        let limits = Limits::new(Some(1), HashMap::new());
+        let running = Running::default();
+        let first = start(&running, Concurrency::Allow).unwrap().unwrap();
+        let first_pid = pid(&first);
+        let first = reap(&running, first);
+
+        // A queued run waits for the previous one without taking a slot
+        let entry = parse_crontab("&concurrency(queue) @daily true").unwrap().remove(0);
+        let queued = {
+            let (history, limits, running) = (history.clone(), limits.clone(), running.clone());
+            thread::spawn(move || execute(&entry, Local::now(), 1, &history, &limits, &running))
+        };
+        thread::sleep(time::Duration::from_millis(100));
+        let (acquired, slot) = channel();
+        let waiting = limits.clone();
+        thread::spawn(move || acquired.send(waiting.acquire(None)));
+        drop(slot.recv_timeout(time::Duration::from_secs(5)).unwrap());
+
+        running.terminate(first_pid);
+        first.join().unwrap();
+        assert_eq!(queued.join().unwrap().unwrap().exit_code, Some(0));
+    }
+
+    #[test]
+    fn retries_before_next_run() {
+        use crate::parser::parse_crontab;
+
+        let time = |s| Local.datetime_from_str(s, "%Y-%m-%d %H:%M:%S").unwrap();
+        let entry = parse_crontab("0 * * * * cmd").unwrap().remove(0);
+        let now = time("2019-08-01 10:59:30");
+        assert!(retry_fits(&entry, now, Duration::seconds(10)));
+        assert!(!retry_fits(&entry, now, Duration::minutes(1)));
+        // A failed catch-up run of 08:00 is retried in time for 11:00
+        assert!(retry_fits(&entry, time("2019-08-01 10:30:00"), Duration::minutes(5)));
+        let boot = parse_crontab("@reboot cmd").unwrap().remove(0);
+        assert!(retry_fits(&boot, now, Duration::hours(2)));
+    }
+
+    #[test]
+    fn closure_jobs() {
+        use crate::history::Filter;
+        use crate::schedule::Schedule;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let dir = TempDir::new("closures");
+        let history = History::new(dir.path());
+        let limits = Limits::default();
+        let running = Running::default();
+        let runs = Arc::new(AtomicUsize::new(0));
+
+        let mut entry = CronEntry::new("count", Schedule::default());
+        let counter = runs.clone();
+        entry.set_job(Job::Fn(Box::new(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        })));
+        run_job(&entry, Local::now(), &history, &limits, &Mailer::default(), &running).join().unwrap();
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+
+        let mut entry = CronEntry::new("panic", Schedule::default());
+        entry.set_job(Job::Fn(Box::new(|| panic!("failed run"))));
+        run_job(&entry, Local::now(), &history, &limits, &Mailer::default(), &running).join().unwrap();
+
+        // A closure that runs too long times out, but it is still running
+        // and keeps further runs from starting until it returns
+        let mut entry = CronEntry::new("slow", Schedule::default());
+        entry.set_job(Job::Fn(Box::new(|| thread::sleep(time::Duration::from_millis(500)))));
+        entry.options.timeout = Some(Duration::milliseconds(100));
+        entry.options.concurrency = Concurrency::Skip;
+        run_job(&entry, Local::now(), &history, &limits, &Mailer::default(), &running).join().unwrap();
+        assert_eq!(running.count(), 1);
+        run_job(&entry, Local::now(), &history, &limits, &Mailer::default(), &running).join().unwrap();
+        // and `replace` waits for it instead of running a second copy
+        entry.options.concurrency = Concurrency::Replace;
+        run_job(&entry, Local::now(), &history, &limits, &Mailer::default(), &running).join().unwrap();
+        thread::sleep(time::Duration::from_millis(600));
+        assert_eq!(running.count(), 0);
+
+        let records = history.query(&Filter::default()).unwrap();
+        let codes: Vec<_> = records.iter().map(|r| (r.exit_code, r.timed_out)).collect();
+        assert_eq!(
+            codes,
+            vec![(Some(0), false), (Some(PANIC_EXIT_CODE), false), (None, true), (None, true)]
+        );
+        assert!(records.iter().all(|r| r.output_path.is_none()));
+        assert!(records[3].start - records[2].start >= Duration::milliseconds(500));
+    }
+    #[test]
+    fn user_environment() {
+        use crate::parser::parse_crontab;
+        use crate::system::User;
+        use std::path::PathBuf;
+
+        let mut entry = parse_crontab("@daily env").unwrap().remove(0);
+        let user = User {
+            name: String::from("someone"),
+            uid: unsafe { libc::geteuid() },
+            gid: unsafe { libc::getegid() },
+            groups: Vec::new(),
+            home: PathBuf::from("/nonexistent"),
+            shell: PathBuf::from("/bin/sh"),
+        };
+        user.assign(&mut entry);
+        entry.env.push((String::from("MAILTO"), String::from("someone")));
+        let output = shell_command(&entry, "env").output().unwrap();
+        let env = String::from_utf8(output.stdout).unwrap();
+        let names: Vec<&str> = env.lines().filter_map(|line| line.split('=').next()).collect();
+        for (name, _) in std::env::vars() {
+            let own = ["PATH", "HOME", "SHELL", "LOGNAME", "USER", "PWD", "SHLVL", "_"];
+            assert!(own.contains(&name.as_str()) || !names.contains(&name.as_str()), "{}", name);
+        }
+        assert!(env.lines().any(|line| line == "PATH=/usr/bin:/bin"));
+        assert!(env.lines().any(|line| line == "USER=someone"));
+        assert!(env.lines().any(|line| line == "MAILTO=someone"));
+    }
+}
@@ -0,0 +1,436 @@
+//! Matching wall clock times against the time fields of an entry.
+//!
+//! The search for the next matching time works from the largest unit down.
+//! Whenever a field does not match, the time jumps straight to the next
+//! value of that field found in its bitmask, resetting all smaller units.
+//! The calendar repeats every 400 years, so a schedule without a run in
+//! that time never runs, like `0 0 31 2 *`, and the search gives up.
+use chrono::prelude::*;
+use chrono::Duration;
+use std::fmt;
+
+use crate::expr::*;
+
+/// The years after which the weekdays and leap years of the Gregorian
+/// calendar repeat
+const CYCLE_YEARS: i32 = 400;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Schedule {
+    /// Always 0 unless the entry has a seconds field
+    pub second: CronExpr,
+    pub minute: CronExpr,
+    pub hour: CronExpr,
+    pub dom: CronExpr,
+    pub month: CronExpr,
+    pub dow: CronExpr,
+    pub year: CronExpr,
+}
+
+impl Default for Schedule {
+    /// Every minute
+    fn default() -> Schedule {
+        Schedule {
+            second: CronExpr(CronUnit::Second, CronInterval::Multiple(Bits(1))),
+            minute: CronExpr(CronUnit::Minute, CronInterval::Every),
+            hour: CronExpr(CronUnit::Hour, CronInterval::Every),
+            dom: CronExpr(CronUnit::DayOfMonth, CronInterval::Every),
+            month: CronExpr(CronUnit::Month, CronInterval::Every),
+            dow: CronExpr(CronUnit::DayOfWeek, CronInterval::Every),
+            year: CronExpr(CronUnit::Year, CronInterval::Every),
+        }
+    }
+}
+
+impl Schedule {
+    /// Whether the entry needs a seconds field to give its schedule
+    pub fn has_seconds(&self) -> bool {
+        self.second.1 != CronInterval::Multiple(Bits(1))
+    }
+
+    /// Whether the schedule is limited to some years
+    pub fn has_year(&self) -> bool {
+        !self.year.is_every()
+    }
+
+    /// Whether a field has no values at all, then nothing ever matches
+    fn is_empty(&self) -> bool {
+        [&self.second, &self.minute, &self.hour, &self.month]
+            .iter()
+            .any(|field| field.bits().is_empty())
+    }
+
+    /// The first time at or after `now`, truncated to the second, that
+    /// matches. None once the last year of the schedule is over, or if the
+    /// schedule never matches.
+    pub fn next(&self, now: DateTime<Local>) -> Option<DateTime<Local>> {
+        let seconds = self.second.bits();
+        let minutes = self.minute.bits();
+        let hours = self.hour.bits();
+        let months = self.month.bits();
+        if self.is_empty() {
+            return None;
+        }
+
+        let mut t = now.naive_local().with_nanosecond(0).unwrap();
+        let mut limit = t.year() + CYCLE_YEARS;
+        loop {
+            if t.year() > limit {
+                return None;
+            }
+            if !self.year.contains(t.year() as u32) {
+                let year = match &self.year {
+                    CronExpr(_, CronInterval::Years(years)) => {
+                        *years.iter().find(|year| **year > t.year() as u32)?
+                    }
+                    // A year field is a list of years or `*`, nothing else
+                    // can be searched for a year
+                    _ => return None,
+                };
+                t = NaiveDate::from_ymd(year as i32, 1, 1).and_hms(0, 0, 0);
+                limit = t.year() + CYCLE_YEARS;
+                continue;
+            }
+
+            match months.next(t.month()) {
+                Some(month) if month == t.month() => {}
+                Some(month) => {
+                    t = NaiveDate::from_ymd(t.year(), month, 1).and_hms(0, 0, 0);
+                    continue;
+                }
+                None => {
+                    let month = months.first()?;
+                    t = NaiveDate::from_ymd(t.year() + 1, month, 1).and_hms(0, 0, 0);
+                    continue;
+                }
+            }
+
+            if !self.day_matches(t.date()) {
+                t = match self.next_day(t.date()) {
+                    Some(day) => day.and_hms(0, 0, 0),
+                    None => {
+                        let last = last_day_of_month(t.year(), t.month());
+                        t.date().with_day(last).unwrap().succ().and_hms(0, 0, 0)
+                    }
+                };
+                continue;
+            }
+
+            match hours.next(t.hour()) {
+                Some(hour) if hour == t.hour() => {}
+                Some(hour) => {
+                    t = t.date().and_hms(hour, 0, 0);
+                    continue;
+                }
+                None => {
+                    t = t.date().succ().and_hms(0, 0, 0);
+                    continue;
+                }
+            }
+
+            match minutes.next(t.minute()) {
+                Some(minute) if minute == t.minute() => {}
+                Some(minute) => {
+                    t = t.date().and_hms(t.hour(), minute, 0);
+                    continue;
+                }
+                None => {
+                    t = t.date().and_hms(t.hour(), 0, 0) + Duration::hours(1);
+                    continue;
+                }
+            }
+
+            match seconds.next(t.second()) {
+                Some(second) if second == t.second() => {}
+                Some(second) => {
+                    t = t.with_second(second).unwrap();
+                    continue;
+                }
+                None => {
+                    t = t.date().and_hms(t.hour(), t.minute(), 0) + Duration::minutes(1);
+                    continue;
+                }
+            }
+
+            // Skip times that do not exist because of a DST change
+            match Local.from_local_datetime(&t).earliest() {
+                Some(next) => return Some(next),
+                None => t += Duration::seconds(1),
+            }
+        }
+    }
+
+    /// The last time at or before `now`, truncated to the second, that
+    /// matches. None before the first year of the schedule, or if the
+    /// schedule never matches.
+    pub fn prev(&self, now: DateTime<Local>) -> Option<DateTime<Local>> {
+        let seconds = self.second.bits();
+        let minutes = self.minute.bits();
+        let hours = self.hour.bits();
+        let months = self.month.bits();
+        if self.is_empty() {
+            return None;
+        }
+
+        let mut t = now.naive_local().with_nanosecond(0).unwrap();
+        let mut limit = t.year() - CYCLE_YEARS;
+        loop {
+            if t.year() < limit {
+                return None;
+            }
+            if !self.year.contains(t.year() as u32) {
+                let year = match &self.year {
+                    CronExpr(_, CronInterval::Years(years)) => {
+                        *years.iter().rev().find(|year| **year < t.year() as u32)?
+                    }
+                    _ => return None,
+                };
+                t = NaiveDate::from_ymd(year as i32, 12, 31).and_hms(23, 59, 59);
+                limit = t.year() - CYCLE_YEARS;
+                continue;
+            }
+
+            match months.prev(t.month()) {
+                Some(month) if month == t.month() => {}
+                Some(month) => {
+                    let last = last_day_of_month(t.year(), month);
+                    t = NaiveDate::from_ymd(t.year(), month, last).and_hms(23, 59, 59);
+                    continue;
+                }
+                None => {
+                    let month = months.last()?;
+                    let last = last_day_of_month(t.year() - 1, month);
+                    t = NaiveDate::from_ymd(t.year() - 1, month, last).and_hms(23, 59, 59);
+                    continue;
+                }
+            }
+
+            if !self.day_matches(t.date()) {
+                t = match self.prev_day(t.date()) {
+                    Some(day) => day.and_hms(23, 59, 59),
+                    None => t.date().with_day(1).unwrap().pred().and_hms(23, 59, 59),
+                };
+                continue;
+            }
+
+            match hours.prev(t.hour()) {
+                Some(hour) if hour == t.hour() => {}
+                Some(hour) => {
+                    t = t.date().and_hms(hour, 59, 59);
+                    continue;
+                }
+                None => {
+                    t = t.date().pred().and_hms(23, 59, 59);
+                    continue;
+                }
+            }
+
+            match minutes.prev(t.minute()) {
+                Some(minute) if minute == t.minute() => {}
+                Some(minute) => {
+                    t = t.date().and_hms(t.hour(), minute, 59);
+                    continue;
+                }
+                None => {
+                    t = t.date().and_hms(t.hour(), 0, 0) - Duration::seconds(1);
+                    continue;
+                }
+            }
+
+            match seconds.prev(t.second()) {
+                Some(second) if second == t.second() => {}
+                Some(second) => {
+                    t = t.with_second(second).unwrap();
+                    continue;
+                }
+                None => {
+                    t = t.date().and_hms(t.hour(), t.minute(), 0) - Duration::seconds(1);
+                    continue;
+                }
+            }
+
+            match Local.from_local_datetime(&t).earliest() {
+                Some(prev) => return Some(prev),
+                None => t -= Duration::seconds(1),
+            }
+        }
+    }
+
+    /// The next matching day after `date` in the same month
+    fn next_day(&self, date: NaiveDate) -> Option<NaiveDate> {
+        let last = last_day_of_month(date.year(), date.month());
+        if let (CronInterval::Multiple(days), true) = (&self.dom.1, self.dow.is_every()) {
+            return days
+                .next(date.day() + 1)
+                .filter(|day| *day <= last)
+                .map(|day| date.with_day(day).unwrap());
+        }
+        (date.day() + 1..=last)
+            .map(|day| date.with_day(day).unwrap())
+            .find(|date| self.day_matches(*date))
+    }
+
+    /// The last matching day before `date` in the same month
+    fn prev_day(&self, date: NaiveDate) -> Option<NaiveDate> {
+        if let (CronInterval::Multiple(days), true) = (&self.dom.1, self.dow.is_every()) {
+            return days
+                .prev(date.day() - 1)
+                .filter(|day| *day >= 1)
+                .map(|day| date.with_day(day).unwrap());
+        }
+        (1..date.day())
+            .rev()
+            .map(|day| date.with_day(day).unwrap())
+            .find(|date| self.day_matches(*date))
+    }
+
+    /// Like cron, a day matches if either the day of month or the day of week
+    /// matches when both are restricted
+    pub fn day_matches(&self, date: NaiveDate) -> bool {
+        let dom = self.dom.contains_day(date);
+        let dow = self.dow.contains_day(date);
+        if self.dom.is_multiple() && self.dow.is_multiple() {
+            dom || dow
+        } else {
+            dom && dow
+        }
+    }
+}
+
+/// The time fields as written in a crontab, with a leading seconds field if
+/// the entry needs one and a trailing year field if it has one
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.has_seconds() {
+            write!(f, "{} ", self.second.1)?;
+        }
+        write!(
+            f,
+            "{} {} {} {} {}",
+            self.minute.1, self.hour.1, self.dom.1, self.month.1, self.dow.1
+        )?;
+        if !self.year.is_every() {
+            write!(f, " {}", self.year.1)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field(unit: CronUnit, values: &[u32]) -> CronExpr {
+        CronExpr(unit, CronInterval::Multiple(values.iter().cloned().collect()))
+    }
+
+    /// Step through every hour and then every minute, the slow way
+    fn brute_force(schedule: &Schedule, now: DateTime<Local>) -> DateTime<Local> {
+        let mut t = now.with_second(0).unwrap();
+        loop {
+            if !schedule.month.contains(t.month())
+                || !schedule.day_matches(t.naive_local().date())
+                || !schedule.hour.contains(t.hour())
+            {
+                t = t.with_minute(0).unwrap() + Duration::hours(1);
+            } else if !schedule.minute.contains(t.minute()) {
+                t = t + Duration::minutes(1);
+            } else {
+                return t;
+            }
+        }
+    }
+
+    /// Step back through every hour and then every minute
+    fn brute_force_back(schedule: &Schedule, now: DateTime<Local>) -> DateTime<Local> {
+        let mut t = now.with_second(0).unwrap();
+        loop {
+            if !schedule.month.contains(t.month())
+                || !schedule.day_matches(t.naive_local().date())
+                || !schedule.hour.contains(t.hour())
+            {
+                t = t.with_minute(0).unwrap() - Duration::minutes(1);
+            } else if !schedule.minute.contains(t.minute()) {
+                t = t - Duration::minutes(1);
+            } else {
+                return t;
+            }
+        }
+    }
+
+    #[test]
+    fn matches_brute_force() {
+        let schedules = vec![
+            Schedule::default(),
+            Schedule {
+                minute: field(CronUnit::Minute, &[0, 30]),
+                hour: field(CronUnit::Hour, &[3, 23]),
+                ..Schedule::default()
+            },
+            Schedule {
+                minute: field(CronUnit::Minute, &[15]),
+                dom: field(CronUnit::DayOfMonth, &[13, 31]),
+                month: field(CronUnit::Month, &[2, 4, 12]),
+                ..Schedule::default()
+            },
+            Schedule {
+                minute: field(CronUnit::Minute, &[59]),
+                hour: field(CronUnit::Hour, &[0]),
+                dom: field(CronUnit::DayOfMonth, &[13]),
+                dow: field(CronUnit::DayOfWeek, &[5]),
+                ..Schedule::default()
+            },
+        ];
+        let mut now = Utc.ymd(2019, 12, 30).and_hms(22, 17, 0).with_timezone(&Local);
+        for _ in 0..100 {
+            for schedule in &schedules {
+                assert_eq!(schedule.next(now), Some(brute_force(schedule, now)));
+                assert_eq!(schedule.prev(now), Some(brute_force_back(schedule, now)));
+            }
+            now = now + Duration::minutes(7919);
+        }
+    }
+
+    #[test]
+    fn never_matches() {
+        let now = Local.ymd(2019, 8, 1).and_hms(0, 0, 0);
+        let schedules = vec![
+            Schedule {
+                minute: field(CronUnit::Minute, &[]),
+                ..Schedule::default()
+            },
+            Schedule {
+                second: field(CronUnit::Second, &[]),
+                ..Schedule::default()
+            },
+            Schedule {
+                dom: field(CronUnit::DayOfMonth, &[31]),
+                month: field(CronUnit::Month, &[2]),
+                ..Schedule::default()
+            },
+            Schedule {
+                dom: field(CronUnit::DayOfMonth, &[]),
+                ..Schedule::default()
+            },
+            Schedule {
+                year: field(CronUnit::Year, &[1]),
+                ..Schedule::default()
+            },
+        ];
+        for schedule in &schedules {
+            assert_eq!(schedule.next(now), None);
+            assert_eq!(schedule.prev(now), None);
+        }
+
+        // The 29th of February is found however far away it is
+        let leap_day = Schedule {
+            dom: field(CronUnit::DayOfMonth, &[29]),
+            month: field(CronUnit::Month, &[2]),
+            ..Schedule::default()
+        };
+        assert_eq!(
+            leap_day.next(Local.ymd(2097, 3, 1).and_hms(0, 0, 0)),
+            Some(Local.ymd(2104, 2, 29).and_hms(0, 0, 0))
+        );
+    }
+}
@@ -0,0 +1,591 @@
+//! Running the jobs of a crontab, each on a thread of its own that sleeps
+//! until the next run is due.
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+
+use chrono::prelude::*;
+use chrono::Duration;
+
+use crate::clock::{random_duration, sleep_until, Wakeup};
+use crate::entry::CronEntry;
+use crate::history::{self, History};
+use crate::limits::Limits;
+use crate::mail::Mailer;
+use crate::options::{CatchUp, JobOptions};
+use crate::runner::{run_job, save_last_run, shell_command, Running};
+use crate::sources::Sources;
+use crate::Error;
+
+/// How often the condition of an @reboot job with `after` is checked
+pub(crate) const CONDITION_POLL_SECONDS: i64 = 10;
+
+pub enum Message {
+    Quit,
+}
+/// A handle to a started job, clones of it control the same job
+#[derive(Clone)]
+pub struct CronJob {
+    id: u64,
+    entry: CronEntry,
+    tx: Sender<Message>,
+    running: Running,
+    history: History,
+    limits: Limits,
+    mailer: Mailer,
+}
+
+impl CronJob {
+    /// Tells the job apart from others of the same scheduler, even ones
+    /// with the same command
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+    pub fn cmd(&self) -> &str {
+        &self.entry.cmd
+    }
+    /// Process ids of the runs that are still going
+    pub fn running(&self) -> Vec<u32> {
+        self.running.pids()
+    }
+    /// Whether a run is still going, of a command or of a closure
+    pub fn is_running(&self) -> bool {
+        self.running.count() > 0
+    }
+    /// Run the job now, outside of its schedule. The run follows the options
+    /// of the job like a scheduled run, the handle finishes with it.
+    pub fn trigger(&self) -> JoinHandle<()> {
+        println!("Triggering: `{}`", self.entry.cmd);
+        run_job(
+            &self.entry,
+            Local::now(),
+            &self.history,
+            &self.limits,
+            &self.mailer,
+            &self.running,
+        )
+    }
+    /// Stop scheduling the job and interrupt pending retries
+    pub fn cancel(&self) {
+        self.running.cancel();
+        // The job has already stopped if it was cancelled before
+        let _ = self.tx.send(Message::Quit);
+    }
+}
+
+/// What the jobs of a scheduler are started with, both schedulers are set
+/// up through `Configure`
+#[derive(Clone, Debug)]
+pub struct Setup {
+    pub(crate) sources: Sources,
+    pub(crate) seconds: bool,
+    pub(crate) history: History,
+    pub(crate) limits: Limits,
+    pub(crate) mailer: Mailer,
+    default_timeout: Option<Duration>,
+    env: Vec<(String, String)>,
+}
+
+impl Setup {
+    pub(crate) fn new(cron_path: String, history: History) -> Setup {
+        let mut sources = Sources::default();
+        sources.add_file(cron_path);
+        Setup {
+            sources,
+            seconds: false,
+            history,
+            limits: Limits::default(),
+            mailer: Mailer::default(),
+            default_timeout: None,
+            env: Vec::new(),
+        }
+    }
+
+    /// `entry` with the default timeout and the variables for every job
+    pub(crate) fn prepare(&self, mut entry: CronEntry) -> CronEntry {
+        if entry.options.timeout.is_none() {
+            entry.options.timeout = self.default_timeout;
+        }
+        entry.env = self.env.iter().cloned().chain(entry.env).collect();
+        entry
+    }
+}
+
+/// The settings of a scheduler, they must be made before jobs are started
+pub trait Configure {
+    fn setup(&mut self) -> &mut Setup;
+
+    /// Limit how many jobs run at once
+    fn set_limits(&mut self, limits: Limits) {
+        self.setup().limits = limits;
+    }
+
+    /// Read the crontab at `path` too
+    fn add_crontab<P: Into<PathBuf>>(&mut self, path: P) {
+        self.setup().sources.add_file(path);
+    }
+
+    /// Read the crontabs in the drop-in directory `dir` too, see `sources`
+    fn add_crontab_dir<P: Into<PathBuf>>(&mut self, dir: P) {
+        self.setup().sources.add_dir(dir);
+    }
+
+    /// Read the crontabs of users in the spool directory `dir` too, see
+    /// `system`
+    fn add_spool_dir<P: Into<PathBuf>>(&mut self, dir: P) {
+        self.setup().sources.add_spool_dir(dir);
+    }
+
+    /// Read the crontabs and crontab directories in the system format, with
+    /// the user of each entry before its command
+    fn set_system(&mut self, system: bool) {
+        self.setup().sources.set_system(system);
+    }
+
+    /// Read the crontabs as if they started with `!seconds(on)`
+    fn set_seconds(&mut self, seconds: bool) {
+        self.setup().seconds = seconds;
+    }
+
+    /// Limit the runtime of jobs that do not set a timeout themselves
+    fn set_default_timeout(&mut self, timeout: Option<Duration>) {
+        self.setup().default_timeout = timeout;
+    }
+
+    /// Mail the output of runs to the `MAILTO` of their entries
+    fn set_mailer(&mut self, mailer: Mailer) {
+        self.setup().mailer = mailer;
+    }
+
+    /// Variables for every job, the variables of an entry take precedence.
+    /// `SHELL` picks the shell that runs the commands.
+    fn set_env(&mut self, env: Vec<(String, String)>) {
+        self.setup().env = env;
+    }
+}
+
+pub struct CronScheduler {
+    setup: Setup,
+    jobs: HashMap<u64, CronJob>,
+    next_id: u64,
+}
+
+impl Configure for CronScheduler {
+    fn setup(&mut self) -> &mut Setup {
+        &mut self.setup
+    }
+}
+
+impl CronScheduler {
+    /// Read the crontab at `cron_path`, more can be added before reading
+    pub fn new(cron_path: String, history: History) -> CronScheduler {
+        CronScheduler {
+            setup: Setup::new(cron_path, history),
+            jobs: HashMap::new(),
+            next_id: 0,
+        }
+    }
+
+    /// Start the jobs of the crontabs, none of them if one does not parse
+    pub fn read_crontab(&mut self) -> Result<(), Error> {
+        for entry in self.setup.sources.read(self.setup.seconds)? {
+            self.start_job(entry);
+        }
+        Ok(())
+    }
+
+    /// Schedule `entry`, the returned handle cancels or triggers it
+    pub fn start_job(&mut self, entry: CronEntry) -> CronJob {
+        let Setup {
+            history,
+            limits,
+            mailer,
+            ..
+        } = &self.setup;
+        let entry = self.setup.prepare(entry);
+        let (tx, rx) = channel();
+        let running = Running::default();
+        spawn_job(&entry, rx, history, limits, mailer, &running);
+        self.next_id += 1;
+        let cronjob = CronJob {
+            id: self.next_id,
+            entry,
+            tx,
+            running,
+            history: history.clone(),
+            limits: limits.clone(),
+            mailer: mailer.clone(),
+        };
+        self.jobs.insert(cronjob.id, cronjob.clone());
+        cronjob
+    }
+
+    /// The started job with the id `id`
+    pub fn job(&self, id: u64) -> Option<&CronJob> {
+        self.jobs.get(&id)
+    }
+
+    /// The started jobs named `name`, see `CronEntry::job_name`
+    pub fn jobs<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a CronJob> + 'a {
+        self.jobs.values().filter(move |job| job.entry.job_name() == name)
+    }
+
+    pub fn clear(&mut self) {
+        for job in self.jobs.values() {
+            job.cancel();
+        }
+        self.jobs.clear();
+    }
+}
+/// The runs of `entry` between `last_run` and `now` that its catch-up policy
+/// asks for, oldest first
+pub(crate) fn missed_runs(
+    entry: &CronEntry,
+    last_run: DateTime<Local>,
+    now: DateTime<Local>,
+) -> Vec<DateTime<Local>> {
+    let limit = match entry.options.catchup {
+        CatchUp::Skip => return Vec::new(),
+        CatchUp::Once => 1,
+        CatchUp::All(limit) => limit as usize,
+    };
+    // Only the latest runs are kept, so look for them from the end
+    let mut runs: Vec<_> = entry
+        .iter_between(last_run + Duration::seconds(1), now)
+        .rev()
+        .take(limit)
+        .collect();
+    runs.reverse();
+    runs
+}
+
+/// How long to wait until catch-up runs may start, see `start_hours`
+pub(crate) fn start_window_delay(options: &JobOptions, now: DateTime<Local>) -> Duration {
+    let (start, end) = match options.start_hours {
+        Some(hours) => hours,
+        None => return Duration::zero(),
+    };
+    let day = if now.hour() < start {
+        now.date().naive_local()
+    } else if now.hour() >= end {
+        now.date().naive_local().succ()
+    } else {
+        return Duration::zero();
+    };
+    match Local
+        .from_local_datetime(&day.and_hms(start, 0, 0))
+        .earliest()
+    {
+        Some(opening) => opening - now,
+        None => Duration::zero(),
+    }
+}
+
+/// The missed runs of `entry` to catch up on now, per its last run in
+/// `history`
+pub(crate) fn runs_to_catch_up(entry: &CronEntry, history: &History) -> Vec<DateTime<Local>> {
+    let now = Local::now();
+    match history.last_run(&entry.job_name()) {
+        Some(last_run) => missed_runs(entry, last_run, now),
+        None => {
+            // Nothing can have been missed before the job was first seen
+            save_last_run(entry, history, now);
+            Vec::new()
+        }
+    }
+}
+
+/// When to start the catch-up run of `entry` that was missed at `scheduled`
+pub(crate) fn catch_up_start(entry: &CronEntry, scheduled: DateTime<Local>) -> DateTime<Local> {
+    let mut delay = start_window_delay(&entry.options, Local::now());
+    if let Some(max) = entry.options.random_delay {
+        delay = delay + random_duration(max);
+    }
+    println!(
+        "Catching up: `{}` missed at {}, running in {}s",
+        entry.cmd,
+        scheduled,
+        delay.num_seconds()
+    );
+    Local::now() + delay
+}
+
+/// The next run of `entry` and when it starts, None once its schedule has no
+/// more runs. Runs up to `last_fired` are not repeated when the clock was set
+/// back.
+pub(crate) fn next_run(
+    entry: &CronEntry,
+    last_fired: Option<DateTime<Local>>,
+) -> Option<(DateTime<Local>, DateTime<Local>)> {
+    let mut from = Local::now() + Duration::seconds(1);
+    if let Some(last_fired) = last_fired {
+        from = std::cmp::max(from, last_fired + Duration::seconds(1));
+    }
+    let future = match entry.next_execution(from) {
+        Some(future) => future,
+        None => {
+            println!("Retiring: `{}`, its schedule has no more runs", entry.cmd);
+            return None;
+        }
+    };
+    let start = future + entry.start_delay();
+    println!("Scheduling: `{}` for {}", entry.cmd, start);
+    Some((future, start))
+}
+
+/// Whether the @reboot job `entry` already ran since `boot`. Without a boot
+/// id the job runs every time crust starts.
+pub(crate) fn ran_since_boot(entry: &CronEntry, history: &History, boot: &Option<String>) -> bool {
+    let ran = boot.is_some() && history.boot_run(&entry.job_name()) == *boot;
+    if ran {
+        println!("Skipping: `{}`, it already ran since the last boot", entry.cmd);
+    }
+    ran
+}
+
+pub(crate) fn save_boot_run(entry: &CronEntry, history: &History, boot: &Option<String>) {
+    if let Some(boot) = boot {
+        if let Err(e) = history.set_boot_run(&entry.job_name(), boot) {
+            println!("Error saving boot of `{}`: {}", entry.cmd, e);
+        }
+    }
+}
+
+/// Run the missed runs of `entry`, false if the job was cancelled meanwhile
+fn catch_up(
+    entry: &CronEntry,
+    rx: &Receiver<Message>,
+    history: &History,
+    limits: &Limits,
+    mailer: &Mailer,
+    running: &Running,
+) -> bool {
+    for scheduled in runs_to_catch_up(entry, history) {
+        let start = catch_up_start(entry, scheduled);
+        loop {
+            match sleep_until(start, rx) {
+                Wakeup::Reached => break,
+                Wakeup::Quit => return false,
+                // The start is a wall clock time, a jump does not change it
+                Wakeup::Jumped(_) => {}
+            }
+        }
+        let _ = run_job(entry, scheduled, history, limits, mailer, running).join();
+    }
+    true
+}
+
+fn spawn_job(
+    entry: &CronEntry,
+    rx: Receiver<Message>,
+    history: &History,
+    limits: &Limits,
+    mailer: &Mailer,
+    running: &Running,
+) -> JoinHandle<()> {
+    let mut entry = (*entry).clone();
+    let history = history.clone();
+    let limits = limits.clone();
+    let mailer = mailer.clone();
+    let running = running.clone();
+    thread::spawn(move || {
+        if let Some(delay) = entry.startup {
+            run_at_boot(&entry, delay, &rx, &history, &limits, &mailer, &running);
+            return;
+        }
+        entry.set_last_start(history.last_run(&entry.job_name()).unwrap_or_else(Local::now));
+        if catch_up(&entry, &rx, &history, &limits, &mailer, &running) {
+            schedule_loop(&entry, &rx, &history, &limits, &mailer, &running);
+        }
+    })
+}
+
+/// Run an @reboot job, unless it already ran since the system booted
+fn run_at_boot(
+    entry: &CronEntry,
+    delay: Duration,
+    rx: &Receiver<Message>,
+    history: &History,
+    limits: &Limits,
+    mailer: &Mailer,
+    running: &Running,
+) {
+    let boot = history::boot_id();
+    if ran_since_boot(entry, history, &boot) {
+        return;
+    }
+
+    if delay > Duration::zero() {
+        println!("Scheduling: `{}` for {}", entry.cmd, Local::now() + delay);
+        if sleep_until(Local::now() + delay, rx) == Wakeup::Quit {
+            return;
+        }
+    }
+    if let Some(condition) = &entry.options.after {
+        println!(
+            "Waiting: `{}` runs once `{}` succeeds",
+            entry.cmd, condition
+        );
+        while !condition_met(condition, entry) {
+            let poll = Local::now() + Duration::seconds(CONDITION_POLL_SECONDS);
+            if sleep_until(poll, rx) == Wakeup::Quit {
+                return;
+            }
+        }
+    }
+
+    save_boot_run(entry, history, &boot);
+    run_job(entry, Local::now(), history, limits, mailer, running);
+}
+
+fn condition_met(condition: &str, entry: &CronEntry) -> bool {
+    shell_command(entry, condition)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
+
+fn schedule_loop(
+    entry: &CronEntry,
+    rx: &Receiver<Message>,
+    history: &History,
+    limits: &Limits,
+    mailer: &Mailer,
+    running: &Running,
+) {
+    let mut last_fired: Option<DateTime<Local>> = None;
+    while let Some((future, start)) = next_run(entry, last_fired) {
+        match sleep_until(start, rx) {
+            Wakeup::Reached => {}
+            Wakeup::Quit => return,
+            Wakeup::Jumped(jump) => {
+                println!(
+                    "Clock jumped by {}s while waiting to run `{}`",
+                    jump.num_seconds(),
+                    entry.cmd
+                );
+                let late = Local::now() - start;
+                if late < Duration::zero() {
+                    continue;
+                }
+                // Runs that were jumped over are missed runs, unless the jump
+                // only went slightly past them
+                if late > Duration::minutes(1) {
+                    if !catch_up(entry, rx, history, limits, mailer, running) {
+                        return;
+                    }
+                    last_fired = history.last_run(&entry.job_name());
+                    continue;
+                }
+            }
+        }
+
+        last_fired = Some(future);
+        run_job(entry, future, history, limits, mailer, running);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    use crate::parser::parse_crontab;
+
+    fn entry(line: &str) -> CronEntry {
+        parse_crontab(line).unwrap().remove(0)
+    }
+
+    fn time(s: &str) -> DateTime<Local> {
+        Local
+            .from_local_datetime(&NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M").unwrap())
+            .unwrap()
+    }
+
+    #[test]
+    fn catch_up_policy() {
+        let mut e = entry("0 * * * * cmd");
+        let last = time("2019-08-01 10:00");
+        let now = time("2019-08-01 14:30");
+        assert!(missed_runs(&e, last, now).is_empty());
+
+        e.options.catchup = CatchUp::Once;
+        assert_eq!(missed_runs(&e, last, now), vec![time("2019-08-01 14:00")]);
+
+        e.options.catchup = CatchUp::All(3);
+        assert_eq!(
+            missed_runs(&e, last, now),
+            vec![
+                time("2019-08-01 12:00"),
+                time("2019-08-01 13:00"),
+                time("2019-08-01 14:00")
+            ]
+        );
+        assert!(missed_runs(&e, now, now).is_empty());
+    }
+
+    #[test]
+    fn missed_intervals_and_years() {
+        let mut e = entry("&catchup(all) @every 90m cmd");
+        e.set_last_start(time("2019-08-01 10:00"));
+        assert_eq!(
+            missed_runs(&e, time("2019-08-01 10:00"), time("2019-08-01 14:00")),
+            vec![time("2019-08-01 11:30"), time("2019-08-01 13:00")]
+        );
+
+        let mut e = entry("&year(on) 0 12 * * * 2019 cmd");
+        e.options.catchup = CatchUp::All(3);
+        assert_eq!(
+            missed_runs(&e, time("2019-12-30 00:00"), time("2020-01-05 00:00")),
+            vec![time("2019-12-30 12:00"), time("2019-12-31 12:00")]
+        );
+    }
+
+    #[test]
+    fn job_handles() {
+        use crate::entry::Job;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let dir = TempDir::new("handles");
+        let mut scheduler = CronScheduler::new(String::new(), History::new(dir.path()));
+        let runs = Arc::new(AtomicUsize::new(0));
+        let counter = runs.clone();
+        // Far enough away to never run on its own during the test
+        let mut entry = entry("&year(on) 0 0 1 1 * 2099 count");
+        entry.set_job(Job::Fn(Box::new(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        })));
+
+        let job = scheduler.start_job(entry.clone());
+        job.trigger().join().unwrap();
+        scheduler.job(job.id()).unwrap().trigger().join().unwrap();
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+        assert!(!job.is_running());
+
+        // Jobs with the same command are started and cancelled on their own
+        let twin = scheduler.start_job(entry.clone());
+        assert_ne!(twin.id(), job.id());
+        assert_eq!(scheduler.jobs("count").count(), 2);
+        job.cancel();
+        twin.trigger().join().unwrap();
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+
+        // and a job keeps its schedule when another with its command starts
+        let ticks = Arc::new(AtomicUsize::new(0));
+        let counter = ticks.clone();
+        let mut every = self::entry("@every 1s count");
+        every.set_job(Job::Fn(Box::new(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        })));
+        scheduler.start_job(every);
+        scheduler.start_job(entry);
+        thread::sleep(std::time::Duration::from_millis(1500));
+        assert!(ticks.load(Ordering::SeqCst) >= 1);
+        scheduler.clear();
+    }
+}
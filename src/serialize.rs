@@ -0,0 +1,455 @@
+//! Serde support for entries and schedules, behind the `serde` feature.
+//!
+//! Every type has two forms. Human readable formats like JSON, TOML and
+//! YAML get the string form, the same text a crontab would have, and
+//! accept either form. Other formats always use the structured form:
+//!
+//! * `CronUnit`: the name of the unit, "day_of_month" for example
+//! * `CronInterval`: `{every, values, days, years}`, days as "L", "15W",
+//!   "5L" or "1#2"
+//! * `CronExpr`: `{unit, interval}`
+//! * `Schedule`: `{second, minute, hour, day_of_month, month, day_of_week,
+//!   year}`, missing fields are `*`, or 0 for the second
+//!
+//! Within an expression or a schedule the fields may also be given as text,
+//! `{minute = "*/15"}` for example.
+//! * `CronEntry`: `{schedule | every | reboot, command, env, options}`
+use std::collections::BTreeMap;
+use std::fmt;
+use std::marker::PhantomData;
+use std::str::FromStr;
+
+use serde::de::value::MapAccessDeserializer;
+use serde::de::{self, DeserializeOwned, Deserializer, Error as _, MapAccess, Visitor};
+use serde::ser::{Error as _, Serializer};
+use serde::{Deserialize, Serialize};
+
+use crate::entry::{CronEntry, Job};
+use crate::expr::*;
+use crate::options::JobOptions;
+use crate::parser::{format_duration, ParseError};
+use crate::schedule::Schedule;
+
+/// A type with a string form, through Display and FromStr, and a
+/// structured form
+trait Structured: fmt::Display + FromStr<Err = ParseError> {
+    type Fields: Serialize + DeserializeOwned;
+
+    fn to_fields(&self) -> Result<Self::Fields, String>;
+    fn from_fields(fields: Self::Fields) -> Result<Self, ParseError>;
+}
+
+fn serialize<T: Structured, S: Serializer>(value: &T, serializer: S) -> Result<S::Ok, S::Error> {
+    let fields = value.to_fields().map_err(S::Error::custom)?;
+    if serializer.is_human_readable() {
+        serializer.collect_str(value)
+    } else {
+        fields.serialize(serializer)
+    }
+}
+
+fn deserialize<'de, T: Structured, D: Deserializer<'de>>(deserializer: D) -> Result<T, D::Error> {
+    if deserializer.is_human_readable() {
+        deserializer.deserialize_any(EitherForm(PhantomData))
+    } else {
+        T::from_fields(T::Fields::deserialize(deserializer)?).map_err(D::Error::custom)
+    }
+}
+
+struct EitherForm<T>(PhantomData<T>);
+
+impl<'de, T: Structured> Visitor<'de> for EitherForm<T> {
+    type Value = T;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a cron expression or a map of its parts")
+    }
+
+    fn visit_str<E: de::Error>(self, text: &str) -> Result<T, E> {
+        text.parse().map_err(E::custom)
+    }
+
+    fn visit_map<M: MapAccess<'de>>(self, map: M) -> Result<T, M::Error> {
+        let fields = T::Fields::deserialize(MapAccessDeserializer::new(map))?;
+        T::from_fields(fields).map_err(de::Error::custom)
+    }
+}
+
+fn invalid(message: String) -> ParseError {
+    ParseError {
+        path: None,
+        line: 1,
+        message,
+    }
+}
+
+macro_rules! serde_impls {
+    ($($t:ty),*) => {$(
+        impl Serialize for $t {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serialize(self, serializer)
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $t {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<$t, D::Error> {
+                deserialize(deserializer)
+            }
+        }
+    )*};
+}
+
+serde_impls!(CronInterval, CronExpr, Schedule, CronEntry);
+
+impl Serialize for CronUnit {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
+
+impl<'de> Deserialize<'de> for CronUnit {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<CronUnit, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(D::Error::custom)
+    }
+}
+
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default)]
+struct IntervalFields {
+    every: bool,
+    values: Vec<u32>,
+    days: Vec<String>,
+    years: Vec<u32>,
+}
+
+impl Structured for CronInterval {
+    type Fields = IntervalFields;
+
+    fn to_fields(&self) -> Result<IntervalFields, String> {
+        let mut fields = IntervalFields::default();
+        match self {
+            CronInterval::Every => fields.every = true,
+            CronInterval::Multiple(bits) => fields.values = bits.iter().collect(),
+            CronInterval::Calendar(bits, days) => {
+                fields.values = bits.iter().collect();
+                fields.days = days.iter().map(ToString::to_string).collect();
+            }
+            CronInterval::Years(years) => fields.years = years.clone(),
+        }
+        Ok(fields)
+    }
+
+    fn from_fields(fields: IntervalFields) -> Result<CronInterval, ParseError> {
+        let empty = fields.values.is_empty() && fields.days.is_empty() && fields.years.is_empty();
+        if fields.every && !empty {
+            return Err(invalid("`every` excludes other values".to_owned()));
+        }
+        if empty {
+            return Ok(CronInterval::Every);
+        }
+        if !fields.years.is_empty() {
+            if !fields.values.is_empty() || !fields.days.is_empty() {
+                return Err(invalid("`years` excludes other values".to_owned()));
+            }
+            let mut years = fields.years;
+            years.sort_unstable();
+            years.dedup();
+            return CronInterval::from_str(&CronInterval::Years(years).to_string());
+        }
+        if let Some(value) = fields.values.iter().find(|value| **value > 63) {
+            return Err(invalid(format!("Value out of bounds: {}", value)));
+        }
+        let values: Vec<String> = fields.values.iter().map(u32::to_string).collect();
+        values
+            .iter()
+            .chain(&fields.days)
+            .cloned()
+            .collect::<Vec<String>>()
+            .join(",")
+            .parse()
+    }
+}
+
+/// A field within a schedule or an expression, kept as text until its unit
+/// is known, since steps like "*/15" depend on it. The structured form is
+/// that of `CronInterval`.
+struct Field(String);
+
+impl From<&CronInterval> for Field {
+    fn from(interval: &CronInterval) -> Field {
+        Field(interval.to_string())
+    }
+}
+
+impl Serialize for Field {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&self.0)
+        } else {
+            let interval: CronInterval = self.0.parse().map_err(S::Error::custom)?;
+            interval.serialize(serializer)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Field {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Field, D::Error> {
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_any(FieldVisitor)
+        } else {
+            Ok(Field::from(&CronInterval::deserialize(deserializer)?))
+        }
+    }
+}
+
+struct FieldVisitor;
+
+impl<'de> Visitor<'de> for FieldVisitor {
+    type Value = Field;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a cron field or a map of its values")
+    }
+
+    fn visit_str<E: de::Error>(self, text: &str) -> Result<Field, E> {
+        Ok(Field(text.to_owned()))
+    }
+
+    fn visit_map<M: MapAccess<'de>>(self, map: M) -> Result<Field, M::Error> {
+        let fields = IntervalFields::deserialize(MapAccessDeserializer::new(map))?;
+        let interval = CronInterval::from_fields(fields).map_err(de::Error::custom)?;
+        Ok(Field::from(&interval))
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+struct ExprFields {
+    unit: CronUnit,
+    interval: Field,
+}
+
+impl Structured for CronExpr {
+    type Fields = ExprFields;
+
+    fn to_fields(&self) -> Result<ExprFields, String> {
+        Ok(ExprFields {
+            unit: self.0,
+            interval: Field::from(&self.1),
+        })
+    }
+
+    fn from_fields(fields: ExprFields) -> Result<CronExpr, ParseError> {
+        format!("{} {}", fields.unit, fields.interval.0).parse()
+    }
+}
+
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default)]
+struct ScheduleFields {
+    second: Option<Field>,
+    minute: Option<Field>,
+    hour: Option<Field>,
+    day_of_month: Option<Field>,
+    month: Option<Field>,
+    day_of_week: Option<Field>,
+    year: Option<Field>,
+}
+
+impl Structured for Schedule {
+    type Fields = ScheduleFields;
+
+    fn to_fields(&self) -> Result<ScheduleFields, String> {
+        Ok(ScheduleFields {
+            second: Some(Field::from(&self.second.1)),
+            minute: Some(Field::from(&self.minute.1)),
+            hour: Some(Field::from(&self.hour.1)),
+            day_of_month: Some(Field::from(&self.dom.1)),
+            month: Some(Field::from(&self.month.1)),
+            day_of_week: Some(Field::from(&self.dow.1)),
+            year: Some(Field::from(&self.year.1)),
+        })
+    }
+
+    /// Read back through the string form, which checks every field against
+    /// its unit
+    fn from_fields(fields: ScheduleFields) -> Result<Schedule, ParseError> {
+        let text = |field: Option<Field>| field.map_or_else(|| "*".to_owned(), |field| field.0);
+        let mut words = Vec::new();
+        match fields.second {
+            Some(Field(second)) if second != "0" => words.push(second),
+            _ => {}
+        }
+        words.push(text(fields.minute));
+        words.push(text(fields.hour));
+        words.push(text(fields.day_of_month));
+        words.push(text(fields.month));
+        words.push(text(fields.day_of_week));
+        match fields.year {
+            Some(Field(year)) if year != "*" => words.push(year),
+            _ => {}
+        }
+        words.join(" ").parse()
+    }
+}
+
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default)]
+struct EntryFields {
+    schedule: Option<Schedule>,
+    /// `@every`, "90m" or "90m from 00:15"
+    every: Option<String>,
+    /// `@reboot`, how long to wait after the start of crust
+    reboot: Option<String>,
+    command: String,
+    env: BTreeMap<String, String>,
+    options: String,
+}
+
+impl Structured for CronEntry {
+    type Fields = EntryFields;
+
+    fn to_fields(&self) -> Result<EntryFields, String> {
+        if let Job::Fn(_) = self.job() {
+            return Err(format!(
+                "Closure job `{}` can not be serialized",
+                self.cmd()
+            ));
+        }
+        let mut fields = EntryFields {
+            command: self.cmd().to_owned(),
+            env: self.env().iter().cloned().collect(),
+            options: self.options().to_string(),
+            ..EntryFields::default()
+        };
+        match (self.every(), self.startup()) {
+            (_, Some(delay)) => fields.reboot = Some(format_duration(delay)),
+            (Some(every), None) => fields.every = Some(every.to_string()),
+            (None, None) => fields.schedule = Some(self.schedule().clone()),
+        }
+        Ok(fields)
+    }
+
+    fn from_fields(fields: EntryFields) -> Result<CronEntry, ParseError> {
+        let command = fields.command.trim();
+        if command.is_empty() {
+            return Err(invalid(format!("Expected a command: {:?}", fields.command)));
+        }
+        // The command may have several lines, the text only holds a stand-in
+        let mut entry = match (fields.schedule, fields.every, fields.reboot) {
+            (Some(schedule), None, None) => CronEntry::new(command, schedule),
+            (None, Some(every), None) => format!("@every {} -", every).parse()?,
+            (None, None, Some(delay)) if delay.is_empty() => "@reboot -".parse()?,
+            (None, None, Some(delay)) => format!("@reboot+{} -", delay).parse()?,
+            _ => {
+                return Err(invalid(
+                    "Expected one of `schedule`, `every` and `reboot`".to_owned(),
+                ));
+            }
+        };
+        entry.cmd = command.to_owned();
+        entry.set_job(Job::Shell(command.to_owned()));
+        entry.set_env(fields.env.into_iter().collect());
+        let options: JobOptions = fields.options.parse()?;
+        if options.after.is_some() && entry.startup().is_none() {
+            return Err(invalid(
+                "The after option only applies to `reboot` entries".to_owned(),
+            ));
+        }
+        entry.set_options(options);
+        Ok(entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn string_form() {
+        let entry: CronEntry = "&timeout(5m) */15 9-17 * * 1-5 report.sh".parse().unwrap();
+        let value = serde_json::to_value(&entry).unwrap();
+        assert_eq!(
+            value,
+            json!("&timeout(5m) 0,15,30,45 9-17 * * 1-5 report.sh")
+        );
+        let again: CronEntry = serde_json::from_value(value).unwrap();
+        assert_eq!(again.schedule(), entry.schedule());
+        assert_eq!(again.options(), entry.options());
+
+        let expr: CronExpr = serde_json::from_value(json!("day_of_month L,15W")).unwrap();
+        assert_eq!(
+            serde_json::to_value(&expr).unwrap(),
+            json!("day_of_month L,15W")
+        );
+        assert_eq!(
+            serde_json::to_value(CronUnit::DayOfWeek).unwrap(),
+            json!("day_of_week")
+        );
+        let error = serde_json::from_value::<Schedule>(json!("61 * * * *")).unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("Parse error: Value out of bounds"));
+    }
+
+    #[test]
+    fn structured_form() {
+        let schedule: Schedule = serde_json::from_value(json!({
+            "minute": "*/30",
+            "hour": {"values": [9, 17]},
+            "day_of_week": {"days": ["5L"]},
+        }))
+        .unwrap();
+        assert_eq!(schedule.to_string(), "0,30 9,17 * * 5L");
+        assert!(serde_json::from_value::<Schedule>(json!({"hour": {"values": [24]}})).is_err());
+
+        let expr: CronExpr = serde_json::from_value(json!({
+            "unit": "year",
+            "interval": {"years": [2031, 2030]},
+        }))
+        .unwrap();
+        assert_eq!(
+            expr,
+            CronExpr(CronUnit::Year, CronInterval::Years(vec![2030, 2031]))
+        );
+
+        let entry: CronEntry = serde_json::from_value(json!({
+            "every": "90m from 00:15",
+            "command": "sync.sh",
+            "env": {"MAILTO": "ops"},
+            "options": "concurrency(skip)",
+        }))
+        .unwrap();
+        assert_eq!(
+            entry.to_string(),
+            "MAILTO=ops\n&concurrency(skip) @every 1h30m from 00:15 sync.sh"
+        );
+        let entry: CronEntry =
+            serde_json::from_value(json!({"reboot": "", "command": "a"})).unwrap();
+        assert_eq!(entry.startup(), Some(chrono::Duration::zero()));
+        assert!(serde_json::from_value::<CronEntry>(json!({"command": "a"})).is_err());
+        let after = json!({"every": "1h", "command": "a", "options": "after(true)"});
+        assert!(serde_json::from_value::<CronEntry>(after).is_err());
+
+        let fields = entry.to_fields().unwrap();
+        assert_eq!(fields.reboot.as_deref(), Some("0s"));
+        let again = CronEntry::from_fields(fields).unwrap();
+        assert_eq!(again.to_string(), "@reboot a");
+
+        let entry: CronEntry =
+            serde_json::from_value(json!({"every": "1h", "command": "cd /srv\n./sync.sh"}))
+                .unwrap();
+        assert_eq!(entry.cmd(), "cd /srv\n./sync.sh");
+        let text = serde_json::to_string(&entry).unwrap();
+        assert_eq!(serde_json::from_str::<CronEntry>(&text).unwrap().cmd(), entry.cmd());
+    }
+
+    #[test]
+    fn closure_jobs() {
+        let mut entry: CronEntry = "@daily a".parse().unwrap();
+        entry.set_job(Job::Fn(Box::new(|| {})));
+        assert!(serde_json::to_string(&entry).is_err());
+    }
+}
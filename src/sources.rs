@@ -0,0 +1,323 @@
+//! The crontabs a scheduler reads: files given one by one and drop-in
+//! directories like `~/.config/crust/cron.d`.
+//!
+//! Files in a directory are read in the order of their names. Like Debian's
+//! cron, only names made of letters, digits, underscores and hyphens are
+//! read, the rules of run-parts(8). This skips editor backups such as
+//! `job~` or `.job.swp` and leftovers of package managers such as
+//! `job.dpkg-old`.
+//!
+//! In system mode the files and directories are system crontabs with a user
+//! column, and spool directories add the crontabs of users, see `system`.
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::config::expand;
+use crate::crontab::{Crontab, Line, LineKind};
+use crate::entry::CronEntry;
+use crate::parser::{parse_document_in, parse_document_owned};
+use crate::system::{split_users, spool_files, untrusted};
+use crate::Error;
+
+#[derive(Clone, Debug, Default)]
+pub struct Sources {
+    files: Vec<PathBuf>,
+    dirs: Vec<PathBuf>,
+    spool_dirs: Vec<PathBuf>,
+    system: bool,
+}
+
+impl Sources {
+    /// Read the crontab at `path`, it has to exist
+    pub fn add_file<P: Into<PathBuf>>(&mut self, path: P) {
+        self.files.push(path.into());
+    }
+
+    /// Read the crontabs in `dir`, if there is such a directory
+    pub fn add_dir<P: Into<PathBuf>>(&mut self, dir: P) {
+        self.dirs.push(dir.into());
+    }
+
+    /// Read the crontabs of users in the spool directory `dir`, each file
+    /// named after its user
+    pub fn add_spool_dir<P: Into<PathBuf>>(&mut self, dir: P) {
+        self.spool_dirs.push(dir.into());
+    }
+
+    /// Read the files and directories as system crontabs, which name the
+    /// user of each entry before its command
+    pub fn set_system(&mut self, system: bool) {
+        self.system = system;
+    }
+
+    /// The files to read, the given ones first and then those of the
+    /// directories
+    pub fn paths(&self) -> io::Result<Vec<PathBuf>> {
+        let mut paths = self.files.clone();
+        for dir in &self.dirs {
+            paths.append(&mut drop_in_files(dir)?);
+        }
+        Ok(paths)
+    }
+
+    /// The entries of all crontabs, none if one of them does not parse. In
+    /// system mode crontabs that are not root's, or that others can write
+    /// to, are skipped and reported, and so are crontabs of users that do
+    /// not parse.
+    pub fn read(&self, seconds: bool) -> Result<Vec<CronEntry>, Error> {
+        let mut entries = Vec::new();
+        for path in self.paths()? {
+            if !self.system {
+                entries.append(&mut read_file(&path, seconds)?);
+                continue;
+            }
+            if let Some(reason) = untrusted(&path, 0) {
+                println!("Skipping crontab {}: {}", path.display(), reason);
+                continue;
+            }
+            // Root's crontabs may only include crontabs of root too
+            let crontab = read_document(&path, seconds)?;
+            let read = with_path(parse_document_owned(&crontab, &path, 0)?, &path);
+            entries.append(&mut split_users(read).map_err(|e| e.in_file(&path))?);
+        }
+        for dir in &self.spool_dirs {
+            for (path, user) in spool_files(dir)? {
+                // One user's mistake does not stop the jobs of everyone else
+                match read_spool_file(&path, seconds) {
+                    Ok(read) => {
+                        for mut entry in read {
+                            user.clone().assign(&mut entry);
+                            entries.push(entry);
+                        }
+                    }
+                    Err(Error::Io(e)) => println!("Skipping crontab {}: {}", path.display(), e),
+                    Err(Error::Parse(e)) => println!("Skipping crontab: {}", e),
+                }
+            }
+        }
+        Ok(entries)
+    }
+}
+
+/// The entries of the crontab of a user at `path`. Crust reads it as root,
+/// so it may not include other files.
+fn read_spool_file(path: &Path, seconds: bool) -> Result<Vec<CronEntry>, Error> {
+    let crontab = read_document(path, seconds)?;
+    let include = |line: &Line| matches!(line.kind(), LineKind::Include(_));
+    if crontab.lines().iter().any(include) {
+        let message = "crontabs of users can not include others";
+        return Err(Error::Io(io::Error::new(io::ErrorKind::PermissionDenied, message)));
+    }
+    parse_file(&crontab, path)
+}
+
+/// The entries of the crontab at `path`, each knowing where it came from
+pub fn read_file(path: &Path, seconds: bool) -> Result<Vec<CronEntry>, Error> {
+    parse_file(&read_document(path, seconds)?, path)
+}
+
+fn read_document(path: &Path, seconds: bool) -> io::Result<Crontab> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+    Ok(Crontab::parse_with_seconds(&text, seconds))
+}
+
+fn parse_file(crontab: &Crontab, path: &Path) -> Result<Vec<CronEntry>, Error> {
+    Ok(with_path(parse_document_in(crontab, path)?, path))
+}
+
+/// `entries` of the crontab at `path`, those of included crontabs know
+/// their own path already
+fn with_path(mut entries: Vec<CronEntry>, path: &Path) -> Vec<CronEntry> {
+    for entry in &mut entries {
+        entry.source.path.get_or_insert_with(|| path.to_owned());
+    }
+    entries
+}
+
+/// The files of `dir` that follow the naming rules of run-parts(8), sorted
+/// by name. Nothing if the directory does not exist.
+pub fn drop_in_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => {
+            return Err(io::Error::new(
+                e.kind(),
+                format!("{}: {}", dir.display(), e),
+            ))
+        }
+    };
+    let mut paths = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        let valid = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(is_run_parts_name);
+        if valid && path.is_file() {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// The files an `@include` of `pattern` in a crontab in `dir` reads. Paths
+/// are relative to `dir` and may start with `~`. `*` and `?` in the file
+/// name match any files of the directory but hidden ones, sorted by name,
+/// and possibly none. Any other path has to exist.
+pub(crate) fn include_paths(dir: &Path, pattern: &str) -> io::Result<Vec<PathBuf>> {
+    if pattern.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "no path given"));
+    }
+    let path = dir.join(expand(pattern));
+    let name = match path.file_name().and_then(|name| name.to_str()) {
+        Some(name) if name.contains(['*', '?']) => name.to_owned(),
+        _ => return Ok(vec![path]),
+    };
+    let parent = path.parent().unwrap_or_else(|| Path::new(""));
+    let entries = match fs::read_dir(if parent.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        parent
+    }) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut paths = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let matched = file_name.to_str().is_some_and(|file_name| {
+            (!file_name.starts_with('.') || name.starts_with('.')) && glob_match(&name, file_name)
+        });
+        let candidate = parent.join(&file_name);
+        if matched && candidate.is_file() {
+            paths.push(candidate);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// Whether `name` matches `pattern`, where `*` is any text and `?` any
+/// single character
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    // Where the last `*` was and the text it matched up to
+    let mut star = None;
+    let (mut p, mut n) = (0, 0);
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = star {
+            p = star_p + 1;
+            n = star_n + 1;
+            star = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+fn is_run_parts_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn reads_files_and_dirs() {
+        let dir = TempDir::new("sources");
+        let drop_ins = dir.join("cron.d");
+        fs::create_dir_all(drop_ins.join("subdir")).unwrap();
+        fs::write(dir.join("crontab"), "@daily a\n").unwrap();
+        for name in &["b-job", "a_job", "job~", ".job.swp", "job.dpkg-old"] {
+            fs::write(drop_ins.join(name), "# comment\n@hourly c\n").unwrap();
+        }
+
+        let mut sources = Sources::default();
+        sources.add_file(dir.join("crontab"));
+        sources.add_dir(&drop_ins);
+        sources.add_dir(dir.join("missing"));
+        let paths = sources.paths().unwrap();
+        assert_eq!(
+            paths,
+            vec![
+                dir.join("crontab"),
+                drop_ins.join("a_job"),
+                drop_ins.join("b-job")
+            ]
+        );
+
+        let entries = sources.read(false).unwrap();
+        let found: Vec<String> = entries.iter().map(|e| e.source().to_string()).collect();
+        assert_eq!(
+            found,
+            vec![
+                format!("{}:1", dir.join("crontab").display()),
+                format!("{}:2", drop_ins.join("a_job").display()),
+                format!("{}:2", drop_ins.join("b-job").display()),
+            ]
+        );
+
+        fs::write(drop_ins.join("b-job"), "@daily a\n61 * * * * b\n").unwrap();
+        match sources.read(false) {
+            Err(Error::Parse(e)) => {
+                assert_eq!(e.path, Some(drop_ins.join("b-job")));
+                assert_eq!(e.line, 2);
+            }
+            _ => panic!("expected a parse error"),
+        }
+        sources.add_file(dir.join("missing"));
+        fs::remove_file(drop_ins.join("b-job")).unwrap();
+        assert!(matches!(sources.read(false), Err(Error::Io(_))));
+
+        // System crontabs that others can write to are skipped
+        let mut system = Sources::default();
+        system.set_system(true);
+        system.add_file(dir.join("system"));
+        fs::write(dir.join("system"), "@daily root a\n").unwrap();
+        fs::set_permissions(dir.join("system"), fs::Permissions::from_mode(0o666)).unwrap();
+        assert!(system.read(false).unwrap().is_empty());
+    }
+
+    #[test]
+    fn skips_broken_spool_files() {
+        use std::ffi::CStr;
+
+        // Crontabs of the user running the tests, in two spool directories
+        let user = unsafe { CStr::from_ptr((*libc::getpwuid(libc::geteuid())).pw_name) };
+        let user = user.to_str().unwrap();
+        let dir = TempDir::new("spool-errors");
+        let mut sources = Sources::default();
+        sources.set_system(true);
+        for (spool, text) in &[("broken", "61 * * * * x\n"), ("valid", "@daily y\n")] {
+            fs::create_dir(dir.join(spool)).unwrap();
+            fs::write(dir.join(spool).join(user), text).unwrap();
+            fs::set_permissions(dir.join(spool).join(user), fs::Permissions::from_mode(0o600))
+                .unwrap();
+            sources.add_spool_dir(dir.join(spool));
+        }
+
+        let entries = sources.read(false).unwrap();
+        let cmds: Vec<&str> = entries.iter().map(|e| e.cmd()).collect();
+        assert_eq!(cmds, vec!["y"]);
+    }
+}
@@ -0,0 +1,316 @@
+//! Running as the system cron: `/etc/crontab` and `/etc/cron.d` name the
+//! user of each entry in a sixth column before the command, and the spool
+//! directory holds one crontab per user, named after them. Jobs run as
+//! their user, which needs crust to run as root.
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::io;
+use std::mem;
+use std::os::raw::{c_char, c_int};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::ptr;
+
+use crate::entry::{CronEntry, Job};
+use crate::parser::{block_body, ParseError};
+
+/// The crontab of the system
+pub const SYSTEM_CRONTAB: &str = "/etc/crontab";
+/// The drop-in directory of the system, read with the rules of run-parts(8)
+pub const SYSTEM_CRONTAB_DIR: &str = "/etc/cron.d";
+/// Where crontab(1) keeps the crontabs of users on Debian
+pub const SPOOL_DIR: &str = "/var/spool/cron/crontabs";
+/// Where the history is kept in system mode
+pub const LOG_DIR: &str = "/var/lib/crust";
+
+/// A user from the password database and the groups it belongs to
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct User {
+    pub name: String,
+    pub uid: libc::uid_t,
+    pub gid: libc::gid_t,
+    /// The supplementary groups, as initgroups(3) would set them
+    pub groups: Vec<libc::gid_t>,
+    pub home: PathBuf,
+    /// The login shell, /bin/sh if it is not one of /etc/shells, like the
+    /// nologin of system accounts
+    pub shell: PathBuf,
+}
+
+impl User {
+    /// Look up the user `name` and its groups
+    pub fn by_name(name: &str) -> io::Result<User> {
+        let not_found = || io::Error::new(io::ErrorKind::NotFound, format!("No user `{}`", name));
+        let c_name = CString::new(name).map_err(|_| not_found())?;
+        let mut passwd: libc::passwd = unsafe { mem::zeroed() };
+        let mut found: *mut libc::passwd = ptr::null_mut();
+        let mut buf: Vec<c_char> = vec![0; 1024];
+        loop {
+            let error = unsafe {
+                libc::getpwnam_r(
+                    c_name.as_ptr(),
+                    &mut passwd,
+                    buf.as_mut_ptr(),
+                    buf.len(),
+                    &mut found,
+                )
+            };
+            match error {
+                0 => break,
+                libc::ERANGE => buf.resize(buf.len() * 2, 0),
+                error => return Err(io::Error::from_raw_os_error(error)),
+            }
+        }
+        if found.is_null() {
+            return Err(not_found());
+        }
+
+        let text = |field: *const c_char| {
+            unsafe { CStr::from_ptr(field) }
+                .to_string_lossy()
+                .into_owned()
+        };
+        let mut groups: Vec<libc::gid_t> = vec![0; 16];
+        loop {
+            let mut count = groups.len() as c_int;
+            let listed = unsafe {
+                libc::getgrouplist(
+                    c_name.as_ptr(),
+                    passwd.pw_gid,
+                    groups.as_mut_ptr(),
+                    &mut count,
+                )
+            };
+            if listed >= 0 {
+                groups.truncate(count as usize);
+                break;
+            }
+            groups.resize((count as usize).max(groups.len() * 2), 0);
+        }
+        Ok(User {
+            name: name.to_owned(),
+            uid: passwd.pw_uid,
+            gid: passwd.pw_gid,
+            groups,
+            home: PathBuf::from(text(passwd.pw_dir)),
+            shell: login_shell(&text(passwd.pw_shell)),
+        })
+    }
+
+    /// Switches a child process to this user before it runs: its groups,
+    /// then its group and user id. Nothing to do if crust runs as the user
+    /// already. Everything is looked up beforehand, so only system calls
+    /// are left between fork and exec.
+    pub(crate) fn switch(&self) -> Option<impl FnMut() -> io::Result<()> + Send + Sync + 'static> {
+        if self.uid == unsafe { libc::geteuid() } {
+            return None;
+        }
+        let (uid, gid, groups) = (self.uid, self.gid, self.groups.clone());
+        Some(move || {
+            let check = |result: c_int| match result {
+                0 => Ok(()),
+                _ => Err(io::Error::last_os_error()),
+            };
+            check(unsafe { libc::setgroups(groups.len() as _, groups.as_ptr()) })?;
+            check(unsafe { libc::setgid(gid) })?;
+            check(unsafe { libc::setuid(uid) })
+        })
+    }
+
+    /// Give `entry` to this user. `HOME` and `SHELL` become defaults the
+    /// crontab can override, `LOGNAME` and `USER` are always the user.
+    pub(crate) fn assign(self, entry: &mut CronEntry) {
+        let mut env = vec![
+            (
+                String::from("HOME"),
+                self.home.to_string_lossy().into_owned(),
+            ),
+            (
+                String::from("SHELL"),
+                self.shell.to_string_lossy().into_owned(),
+            ),
+        ];
+        env.append(&mut entry.env);
+        env.push((String::from("LOGNAME"), self.name.clone()));
+        env.push((String::from("USER"), self.name.clone()));
+        entry.env = env;
+        entry.user = Some(self);
+    }
+}
+
+fn login_shell(shell: &str) -> PathBuf {
+    let valid = match fs::read_to_string("/etc/shells") {
+        Ok(shells) => shells.lines().any(|line| line.trim() == shell),
+        Err(_) => !shell.is_empty(),
+    };
+    PathBuf::from(if valid { shell } else { "/bin/sh" })
+}
+
+/// Take the user column off the commands of entries read from a system
+/// crontab and give each entry to its user
+pub fn split_users(entries: Vec<CronEntry>) -> Result<Vec<CronEntry>, ParseError> {
+    let mut split = Vec::with_capacity(entries.len());
+    for mut entry in entries {
+        let line = entry.source.line.saturating_sub(1);
+        // Entries of included crontabs know their file
+        let error = |message: String| match &entry.source.path {
+            Some(path) => ParseError::new(line, message).in_file(path),
+            None => ParseError::new(line, message),
+        };
+        let words = entry
+            .cmd
+            .trim_start()
+            .split_once(char::is_whitespace)
+            .map(|(name, cmd)| {
+                let cmd = cmd.trim_start();
+                // A `<<WORD` block after the user holds the script
+                (name.to_owned(), block_body(cmd).unwrap_or(cmd).to_owned())
+            });
+        let (name, cmd) = match words {
+            Some((name, cmd)) if !cmd.trim().is_empty() => (name, cmd),
+            _ => {
+                return Err(error(format!(
+                    "Expected a user and a command, line {}: {}",
+                    line + 1,
+                    entry.cmd
+                )))
+            }
+        };
+        let user = User::by_name(&name).map_err(|e| error(format!("{}, line {}", e, line + 1)))?;
+        if let Job::Shell(_) = *entry.job {
+            entry.set_job(Job::Shell(cmd.clone()));
+        }
+        entry.cmd = cmd;
+        user.assign(&mut entry);
+        split.push(entry);
+    }
+    Ok(split)
+}
+
+/// Why the crontab at `path` may not run jobs as the user `uid`, like cron
+/// it has to belong to that user and only they may write to it. None if it
+/// is fine or cannot be read, reading it reports the error.
+pub(crate) fn untrusted(path: &Path, uid: libc::uid_t) -> Option<String> {
+    let metadata = fs::metadata(path).ok()?;
+    if metadata.uid() != uid {
+        return Some(format!("it belongs to uid {} instead of {}", metadata.uid(), uid));
+    }
+    if metadata.mode() & 0o022 != 0 {
+        return Some(String::from("other users can write to it"));
+    }
+    None
+}
+
+/// The crontabs in the spool directory `dir` with the users they belong
+/// to. Hidden files are skipped, and so are files of users that do not
+/// exist and files that are not trusted, which are reported.
+pub fn spool_files(dir: &Path) -> io::Result<Vec<(PathBuf, User)>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => {
+            return Err(io::Error::new(
+                e.kind(),
+                format!("{}: {}", dir.display(), e),
+            ))
+        }
+    };
+    let mut files = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        let name = match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) if !name.starts_with('.') && path.is_file() => name,
+            _ => continue,
+        };
+        let user = match User::by_name(name) {
+            Ok(user) => user,
+            Err(e) => {
+                println!("Skipping crontab {}: {}", path.display(), e);
+                continue;
+            }
+        };
+        match untrusted(&path, user.uid) {
+            Some(reason) => println!("Skipping crontab {}: {}", path.display(), reason),
+            None => files.push((path, user)),
+        }
+    }
+    files.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_crontab;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn users() {
+        let root = User::by_name("root").unwrap();
+        assert_eq!((root.uid, root.gid), (0, 0));
+        assert!(root.groups.contains(&0));
+        assert!(User::by_name("no such user").is_err());
+        assert_eq!(login_shell("/usr/sbin/nologin"), Path::new("/bin/sh"));
+
+        let crontab = "SHELL=/bin/bash\nHOME=/tmp\n* * * * * root echo hi\n@daily  root\tx y\n";
+        let entries = split_users(parse_crontab(crontab).unwrap()).unwrap();
+        assert_eq!(entries[0].cmd(), "echo hi");
+        assert_eq!(entries[1].cmd(), "x y");
+        assert_eq!(entries[0].user(), Some(&root));
+        assert_eq!(entries[0].var("HOME"), Some("/tmp"));
+        assert_eq!(entries[0].shell(), "/bin/bash");
+        assert_eq!(entries[0].var("LOGNAME"), Some("root"));
+        // Both users may run `echo hi`, the history keeps them apart
+        assert_eq!(entries[0].job_name(), "root: echo hi");
+        assert_eq!(parse_crontab("@daily echo hi").unwrap()[0].job_name(), "echo hi");
+
+        let crontab = "!multiline(on)\n@daily root <<END\ncd /\nls\nEND\n";
+        let entries = split_users(parse_crontab(crontab).unwrap()).unwrap();
+        assert_eq!(entries[0].cmd(), "cd /\nls");
+
+        let entries = parse_crontab("* * * * * root echo hi\n").unwrap();
+        let entries = split_users(entries).unwrap();
+        assert_eq!(entries[0].var("HOME"), Some(root.home.to_str().unwrap()));
+
+        let error = |crontab| split_users(parse_crontab(crontab).unwrap()).unwrap_err();
+        assert_eq!(error("@daily root true\n@daily root\n").line, 2);
+        assert_eq!(error("# users\n@daily nosuchuser x\n").line, 2);
+    }
+
+    #[test]
+    fn trusted_files() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new("spool");
+        let uid = unsafe { libc::geteuid() };
+        let path = dir.join("crontab");
+        fs::write(&path, "@daily true\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+        assert_eq!(untrusted(&path, uid), None);
+        assert!(untrusted(&path, uid + 1).unwrap().contains("belongs to uid"));
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o620)).unwrap();
+        assert!(untrusted(&path, uid).is_some());
+        assert_eq!(untrusted(&dir.join("missing"), uid), None);
+
+        // Only root can give files to others
+        if uid == 0 {
+            for name in &["root", "nobody", "daemon"] {
+                fs::write(dir.join(name), "@daily true\n").unwrap();
+            }
+            fs::set_permissions(dir.join("daemon"), fs::Permissions::from_mode(0o666)).unwrap();
+            let nobody = User::by_name("nobody").unwrap();
+            let chown = |path: &Path| {
+                let path = CString::new(path.to_str().unwrap()).unwrap();
+                unsafe { libc::chown(path.as_ptr(), nobody.uid, nobody.gid) }
+            };
+            let users = || -> Vec<String> {
+                let files = spool_files(&dir).unwrap().into_iter();
+                files.map(|(_, user)| user.name).collect()
+            };
+            // The crontab of nobody is root's, and daemon's is open to all
+            assert_eq!(users(), vec!["root"]);
+            assert_eq!(chown(&dir.join("nobody")), 0);
+            assert_eq!(users(), vec!["nobody", "root"]);
+        }
+    }
+}
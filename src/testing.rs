@@ -0,0 +1,37 @@
+//! Helpers shared by the tests of the crate.
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+/// A directory for the files of a test, removed with everything in it once
+/// the test is done with it, also when the test fails
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// An empty directory, named after the test and the process so that
+    /// tests and test runs do not share one
+    pub fn new(name: &str) -> TempDir {
+        let path = std::env::temp_dir().join(format!("crust-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,512 @@
+//! Running the jobs of a crontab as tokio tasks, for programs that cannot
+//! give crust a thread per job.
+//!
+//! Jobs follow the same schedules, options and history as with the threaded
+//! scheduler. Waiting is done with `tokio::time::sleep_until` in slices, so
+//! changes of the system time are noticed, and commands are run with
+//! `tokio::process`. Closure jobs run on the blocking thread pool.
+use std::collections::HashMap;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::process::Stdio;
+use std::sync::Arc;
+
+use chrono::prelude::*;
+use chrono::Duration;
+use tokio::process::{Child, Command};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+use crate::clock::SLICE_SECONDS;
+use crate::entry::{CronEntry, Job};
+use crate::history::{self, History, Record};
+use crate::limits::Limits;
+use crate::mail::Mailer;
+use crate::runner::{
+    closure_returned, finish_record, job_command, new_record, process_exited, report, retry_delay,
+    save_last_run, shell_command, signal_group, RunId, Running, Started, KILL_GRACE_SECONDS,
+};
+use crate::scheduler::{
+    catch_up_start, next_run, ran_since_boot, runs_to_catch_up, save_boot_run, Configure, Setup,
+    CONDITION_POLL_SECONDS,
+};
+use crate::Error;
+
+/// A started run, a closure reports whether it returned without panicking
+enum Run {
+    Process(Child),
+    Closure(u64, JoinHandle<bool>),
+}
+
+impl Started for Run {
+    fn id(&self) -> RunId {
+        match self {
+            Run::Process(child) => RunId::Process(child.id().unwrap_or(0)),
+            Run::Closure(id, _) => RunId::Closure(*id),
+        }
+    }
+}
+
+/// Sleep until the wall clock reaches `target`, false if the job was
+/// cancelled meanwhile
+async fn sleep_until(target: DateTime<Local>, cancelled: &mut watch::Receiver<bool>) -> bool {
+    loop {
+        if *cancelled.borrow() {
+            return false;
+        }
+        let remaining = target - Local::now();
+        if remaining <= Duration::zero() {
+            return true;
+        }
+        let slice = std::cmp::min(remaining, Duration::seconds(SLICE_SECONDS));
+        let wakeup = Instant::now() + slice.to_std().unwrap();
+        tokio::select! {
+            _ = tokio::time::sleep_until(wakeup) => {}
+            changed = cancelled.changed() => {
+                // The handle is gone, nothing can cancel the job anymore
+                if changed.is_err() {
+                    tokio::time::sleep_until(wakeup).await;
+                }
+            }
+        }
+    }
+}
+
+/// A handle to a started job, clones of it control the same job
+#[derive(Clone)]
+pub struct CronJob {
+    id: u64,
+    entry: CronEntry,
+    cancel: Arc<watch::Sender<bool>>,
+    running: Running,
+    history: History,
+    limits: Limits,
+    mailer: Mailer,
+}
+
+impl CronJob {
+    /// Tells the job apart from others of the same scheduler, even ones
+    /// with the same command
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+    pub fn cmd(&self) -> &str {
+        &self.entry.cmd
+    }
+    /// Process ids of the runs that are still going
+    pub fn running(&self) -> Vec<u32> {
+        self.running.pids()
+    }
+    /// Whether a run is still going, of a command or of a closure
+    pub fn is_running(&self) -> bool {
+        self.running.count() > 0
+    }
+    /// Run the job now, outside of its schedule, see `scheduler::CronJob`
+    pub fn trigger(&self) -> JoinHandle<()> {
+        println!("Triggering: `{}`", self.entry.cmd);
+        let cancelled = self.cancel.subscribe();
+        run_job(
+            &self.entry,
+            Local::now(),
+            &self.history,
+            &self.limits,
+            &self.mailer,
+            &self.running,
+            cancelled,
+        )
+    }
+    /// Stop scheduling the job and interrupt pending retries
+    pub fn cancel(&self) {
+        let _ = self.cancel.send(true);
+    }
+}
+
+/// Like `scheduler::CronScheduler`, but runs the jobs as tasks of the tokio
+/// runtime it is used in
+pub struct CronScheduler {
+    setup: Setup,
+    jobs: HashMap<u64, CronJob>,
+    next_id: u64,
+}
+
+impl Configure for CronScheduler {
+    fn setup(&mut self) -> &mut Setup {
+        &mut self.setup
+    }
+}
+
+impl CronScheduler {
+    /// Read the crontab at `cron_path`, more can be added before reading
+    pub fn new(cron_path: String, history: History) -> CronScheduler {
+        CronScheduler {
+            setup: Setup::new(cron_path, history),
+            jobs: HashMap::new(),
+            next_id: 0,
+        }
+    }
+
+    /// Start the jobs of the crontabs, none of them if one does not parse
+    pub async fn read_crontab(&mut self) -> Result<(), Error> {
+        let (sources, seconds) = (self.setup.sources.clone(), self.setup.seconds);
+        let entries = tokio::task::spawn_blocking(move || sources.read(seconds))
+            .await
+            .map_err(io::Error::other)??;
+        for entry in entries {
+            self.start_job(entry);
+        }
+        Ok(())
+    }
+
+    /// Schedule `entry` on the current runtime, the returned handle cancels
+    /// or triggers it
+    pub fn start_job(&mut self, entry: CronEntry) -> CronJob {
+        let (cancel, cancelled) = watch::channel(false);
+        self.next_id += 1;
+        let cronjob = CronJob {
+            id: self.next_id,
+            entry: self.setup.prepare(entry),
+            cancel: Arc::new(cancel),
+            running: Running::default(),
+            history: self.setup.history.clone(),
+            limits: self.setup.limits.clone(),
+            mailer: self.setup.mailer.clone(),
+        };
+        tokio::spawn(schedule_job(cronjob.clone(), cancelled));
+        self.jobs.insert(cronjob.id, cronjob.clone());
+        cronjob
+    }
+
+    /// The started job with the id `id`
+    pub fn job(&self, id: u64) -> Option<&CronJob> {
+        self.jobs.get(&id)
+    }
+
+    /// The started jobs named `name`, see `CronEntry::job_name`
+    pub fn jobs<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a CronJob> + 'a {
+        self.jobs.values().filter(move |job| job.entry.job_name() == name)
+    }
+
+    pub fn clear(&mut self) {
+        for job in self.jobs.values() {
+            job.cancel();
+        }
+        self.jobs.clear();
+    }
+}
+
+async fn schedule_job(mut job: CronJob, mut cancelled: watch::Receiver<bool>) {
+    if let Some(delay) = job.entry.startup {
+        run_at_boot(&job, delay, cancelled).await;
+        return;
+    }
+    let last_start = job
+        .history
+        .last_run(&job.entry.job_name())
+        .unwrap_or_else(Local::now);
+    job.entry.set_last_start(last_start);
+    if !catch_up(&job, &cancelled).await {
+        return;
+    }
+
+    let mut last_fired: Option<DateTime<Local>> = None;
+    while let Some((future, start)) = next_run(&job.entry, last_fired) {
+        if !sleep_until(start, &mut cancelled).await {
+            return;
+        }
+
+        // Runs that were slept through, because the clock jumped or the
+        // machine was suspended, are missed runs
+        if Local::now() - start > Duration::minutes(1) {
+            if !catch_up(&job, &cancelled).await {
+                return;
+            }
+            last_fired = job.history.last_run(&job.entry.job_name());
+            continue;
+        }
+        last_fired = Some(future);
+        let CronJob {
+            entry,
+            history,
+            limits,
+            mailer,
+            running,
+            ..
+        } = &job;
+        run_job(
+            entry,
+            future,
+            history,
+            limits,
+            mailer,
+            running,
+            cancelled.clone(),
+        );
+    }
+}
+
+/// Run the missed runs of the job, false if it was cancelled meanwhile
+async fn catch_up(job: &CronJob, cancelled: &watch::Receiver<bool>) -> bool {
+    let CronJob {
+        entry,
+        history,
+        limits,
+        mailer,
+        running,
+        ..
+    } = job;
+    for scheduled in runs_to_catch_up(entry, history) {
+        let start = catch_up_start(entry, scheduled);
+        if !sleep_until(start, &mut cancelled.clone()).await {
+            return false;
+        }
+        let run = run_job(
+            entry,
+            scheduled,
+            history,
+            limits,
+            mailer,
+            running,
+            cancelled.clone(),
+        );
+        let _ = run.await;
+    }
+    true
+}
+
+/// Run an @reboot job, unless it already ran since the system booted
+async fn run_at_boot(job: &CronJob, delay: Duration, mut cancelled: watch::Receiver<bool>) {
+    let CronJob {
+        entry,
+        history,
+        limits,
+        mailer,
+        running,
+        ..
+    } = job;
+    let boot = history::boot_id();
+    if ran_since_boot(entry, history, &boot) {
+        return;
+    }
+
+    if delay > Duration::zero() {
+        println!("Scheduling: `{}` for {}", entry.cmd, Local::now() + delay);
+        if !sleep_until(Local::now() + delay, &mut cancelled).await {
+            return;
+        }
+    }
+    if let Some(condition) = &entry.options.after {
+        println!(
+            "Waiting: `{}` runs once `{}` succeeds",
+            entry.cmd, condition
+        );
+        while !condition_met(condition, entry).await {
+            let poll = Local::now() + Duration::seconds(CONDITION_POLL_SECONDS);
+            if !sleep_until(poll, &mut cancelled).await {
+                return;
+            }
+        }
+    }
+
+    save_boot_run(entry, history, &boot);
+    run_job(
+        entry,
+        Local::now(),
+        history,
+        limits,
+        mailer,
+        running,
+        cancelled,
+    );
+}
+
+async fn condition_met(condition: &str, entry: &CronEntry) -> bool {
+    Command::from(shell_command(entry, condition))
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .await
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
+
+/// Run `entry` as a task of its own and record the run in `history` once it
+/// has finished, retrying it if it failed, see `runner::run_job`
+fn run_job(
+    entry: &CronEntry,
+    scheduled: DateTime<Local>,
+    history: &History,
+    limits: &Limits,
+    mailer: &Mailer,
+    running: &Running,
+    mut cancelled: watch::Receiver<bool>,
+) -> JoinHandle<()> {
+    let entry = entry.clone();
+    let history = history.clone();
+    let limits = limits.clone();
+    let mailer = mailer.clone();
+    let running = running.clone();
+    tokio::spawn(async move {
+        let mut attempt = 1;
+        loop {
+            let record =
+                match execute(&entry, scheduled, attempt, &history, &limits, &running).await {
+                    Some(record) => record,
+                    None => {
+                        println!("Skipping: `{}`, the previous run is still going", entry.cmd);
+                        return;
+                    }
+                };
+            // Writing the history and mailing would block the runtime
+            let (report_entry, report_record) = (entry.clone(), record.clone());
+            let (history, mailer) = (history.clone(), mailer.clone());
+            let reported = tokio::task::spawn_blocking(move || {
+                report(&report_entry, &report_record, &history, &mailer)
+            });
+            let _ = reported.await;
+
+            let delay = match retry_delay(&entry, attempt, &record) {
+                Some(delay) => delay,
+                None => return,
+            };
+            attempt += 1;
+            if !sleep_until(Local::now() + delay, &mut cancelled).await {
+                return;
+            }
+        }
+    })
+}
+
+async fn execute(
+    entry: &CronEntry,
+    scheduled: DateTime<Local>,
+    attempt: u32,
+    history: &History,
+    limits: &Limits,
+    running: &Running,
+) -> Option<Record> {
+    let admitted = running.admit_async(entry.options.concurrency).await?;
+    let queued = Local::now();
+    let _slot = limits.acquire_async(entry.options.group.as_deref()).await;
+    let mut record = new_record(entry, scheduled, attempt, Local::now() - queued);
+
+    let closure_id = running.closure_id();
+    let run = admitted.spawn(|| {
+        record.start = Local::now();
+        let cmd = match &*entry.job {
+            Job::Shell(cmd) => cmd,
+            Job::Fn(_) => {
+                let job = entry.job.clone();
+                let running = running.clone();
+                let handle = tokio::task::spawn_blocking(move || {
+                    let ok = match &*job {
+                        Job::Fn(f) => panic::catch_unwind(AssertUnwindSafe(f)).is_ok(),
+                        Job::Shell(_) => true,
+                    };
+                    // Even after a timeout, the run only ends here
+                    running.finished(RunId::Closure(closure_id));
+                    ok
+                });
+                return Ok(Run::Closure(closure_id, handle));
+            }
+        };
+        let command = job_command(entry, cmd, history, &mut record);
+        Command::from(command).spawn().map(Run::Process)
+    });
+
+    save_last_run(entry, history, scheduled);
+    let timeout = entry.options.timeout.filter(|t| *t > Duration::zero());
+    let timeout = timeout.map(|timeout| timeout.to_std().unwrap());
+    match run {
+        Ok(Run::Process(mut child)) => {
+            let pid = child.id().unwrap_or(0);
+            let status = match timeout {
+                Some(timeout) => match tokio::time::timeout(timeout, child.wait()).await {
+                    Ok(status) => status,
+                    Err(_) => {
+                        record.timed_out = true;
+                        signal_group(pid, libc::SIGTERM);
+                        let grace = std::time::Duration::from_secs(KILL_GRACE_SECONDS);
+                        match tokio::time::timeout(grace, child.wait()).await {
+                            Ok(status) => status,
+                            Err(_) => {
+                                signal_group(pid, libc::SIGKILL);
+                                child.wait().await
+                            }
+                        }
+                    }
+                },
+                None => child.wait().await,
+            };
+            running.finished(RunId::Process(pid));
+            process_exited(entry, &mut record, status);
+        }
+        Ok(Run::Closure(_, handle)) => {
+            let returned = match timeout {
+                Some(timeout) => tokio::time::timeout(timeout, handle).await.ok(),
+                None => Some(handle.await),
+            };
+            closure_returned(
+                &mut record,
+                returned.map(|returned| returned.unwrap_or(false)),
+            );
+        }
+        Err(e) => println!("Error running `{}`: {}", entry.cmd, e),
+    }
+    finish_record(&mut record);
+    Some(record)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    use crate::history::Filter;
+    use crate::parser::parse_crontab;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn runs_jobs() {
+        let dir = TempDir::new("tokio");
+        runtime().block_on(async {
+            let mut scheduler = CronScheduler::new(String::new(), History::new(dir.path()));
+            let runs = Arc::new(AtomicUsize::new(0));
+            let counter = runs.clone();
+            let mut entry = parse_crontab("@every 1s count").unwrap().remove(0);
+            entry.set_job(Job::Fn(Box::new(move || {
+                counter.fetch_add(1, Ordering::SeqCst);
+            })));
+            let job = scheduler.start_job(entry);
+            tokio::time::sleep(std::time::Duration::from_millis(2500)).await;
+            job.cancel();
+            assert!(runs.load(Ordering::SeqCst) >= 2);
+
+            // Far enough away to never run on its own during the test
+            let entry = parse_crontab("&timeout(1s),year(on) 0 0 1 1 * 2099 sleep 30")
+                .unwrap()
+                .remove(0);
+            let job = scheduler.start_job(entry);
+            job.trigger().await.unwrap();
+            assert!(!job.is_running());
+            scheduler.clear();
+        });
+
+        let history = History::new(dir.path());
+        let filter = Filter {
+            job: Some(String::from("sleep")),
+            ..Filter::default()
+        };
+        let records = history.query(&filter).unwrap();
+        assert_eq!(records.len(), 1);
+        assert!(records[0].timed_out);
+        assert_eq!(records[0].signal, Some(libc::SIGTERM));
+    }
+}